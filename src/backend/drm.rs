@@ -0,0 +1,530 @@
+use std::{fs,path};
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::io::{self,ErrorKind};
+use std::os::unix::io::{AsFd,BorrowedFd};
+use std::result::Result;
+use drm::buffer::DrmFourcc;
+use drm::control::{self,atomic,connector,crtc,framebuffer,plane,property,AtomicCommitFlags,Device as ControlDevice};
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::{ClientCapability,Device};
+use crate::command;
+use crate::backend::{DisplayBackend,ModeRef,Output,OutputMode,OutputStatus,Vblank};
+use crate::error::Error;
+use crate::mode::{CvtMode,Timings};
+
+
+// Talks to the kernel directly through the DRM mode-setting ioctls, for consoles and
+// machines without a display server. Modes are validated with atomic test-only commits.
+// A mode set this way only lasts while cathode holds the device; afterwards the console
+// restores its own mode, so use the video= kernel parameter for anything permanent.
+pub struct Drm {
+    path: Option<path::PathBuf>,
+    card: Option<Card>,
+    // the framebuffer each CRTC scans out for the last mode we set on it, which has to outlive
+    // the commit; another CRTC's is left alone
+    buffers: Vec<(crtc::Handle, framebuffer::Handle, DumbBuffer)>,
+}
+
+impl Drm {
+    pub fn new(path: Option<&str>) -> Drm {
+        Drm {
+            path: path.map(path::PathBuf::from),
+            card: None,
+            buffers: Vec::new(),
+        }
+    }
+
+    fn open(&mut self, verbose: bool) -> Result<&Card, Error> {
+        if self.card.is_none() {
+            let card = match &self.path {
+                Some(p) => Card::open(p)?,
+                None => Card::find()?,
+            };
+            if verbose {
+                say!("Using DRM device {}.", card.path.to_str().unwrap_or(""));
+            }
+            self.card = Some(card);
+        }
+        Ok(self.card.as_ref().unwrap())
+    }
+
+    fn commit(&mut self, changes: &[(control::Mode, &str)], test_only: bool, verbose: bool) -> Result<(), Error> {
+        // with --dry-run the kernel is only asked whether it would accept the commit
+        let test_only = test_only || command::dry_run();
+        self.open(verbose)?;
+        let card = self.card.as_ref().unwrap();
+        for (crtc, fb, db) in commit(card, changes, test_only)? {
+            // only the framebuffer this CRTC was showing is replaced
+            if let Some(i) = self.buffers.iter().position(|(c, _, _)| *c == crtc) {
+                let (_, old_fb, old_db) = self.buffers.remove(i);
+                let _ = card.destroy_framebuffer(old_fb);
+                let _ = card.destroy_dumb_buffer(old_db);
+            }
+            self.buffers.push((crtc, fb, db));
+        }
+        Ok(())
+    }
+
+    // One of the modes the display already has, by name and closest refresh rate
+    fn existing_mode(&mut self, mode: &OutputMode, display: &str, verbose: bool) -> Result<control::Mode, Error> {
+        let card = self.open(verbose)?;
+        let (_, info) = find_connector(card, display)?;
+        info.modes().iter()
+            .filter(|m| m.name().to_str() == Ok(mode.name.as_str()))
+            .min_by(|a, b| (output_mode(a).rate - mode.rate).abs().partial_cmp(&(output_mode(b).rate - mode.rate).abs()).unwrap())
+            .copied()
+            .ok_or_else(|| Error::ModeNotFound(format!("Display {} has no mode {}.", display, mode.name)))
+    }
+}
+
+impl DisplayBackend for Drm {
+    fn name(&self) -> &'static str {
+        "drm"
+    }
+
+    fn outputs(&mut self, verbose: bool) -> Result<Vec<Output>, Error> {
+        let outputs: Vec<Output> = self.status(verbose)?.into_iter()
+            .filter(|s| s.connected)
+            .map(|s| s.output)
+            .collect();
+        if verbose {
+            for o in &outputs {
+                say!("Found connected display {} running mode {} (EDID {})", o.display, o.current.as_ref().map(|m| m.name.as_str()).unwrap_or("none"), if o.edid.is_some() { "present" } else { "missing" });
+            }
+        }
+        Ok(outputs)
+    }
+
+    fn status(&mut self, verbose: bool) -> Result<Vec<OutputStatus>, Error> {
+        let card = self.open(verbose)?;
+        let res = card.resource_handles()?;
+        let mut outputs: Vec<OutputStatus> = Vec::new();
+        for handle in res.connectors() {
+            let info = card.get_connector(*handle, false)?;
+            let connected = info.state() == connector::State::Connected;
+            let crtc = current_crtc(card, &info).and_then(|c| card.get_crtc(c).ok());
+            let mode = crtc.as_ref().and_then(|c| c.mode());
+            let preferred = info.modes().iter()
+                .find(|m| m.mode_type().contains(control::ModeTypeFlags::PREFERRED))
+                .map(output_mode);
+            outputs.push(OutputStatus {
+                output: Output {
+                    display: connector_name(&info),
+                    current: mode.as_ref().map(output_mode),
+                    preferred,
+                    modes: info.modes().iter().map(output_mode).collect(),
+                    edid: if connected { edid_blob(card, *handle) } else { None },
+                    description: None,
+                },
+                connected,
+                timings: mode.as_ref().map(mode_timings),
+                position: crtc.as_ref().filter(|_| mode.is_some()).map(|c| (c.position().0 as i32, c.position().1 as i32)),
+                // rotation belongs to the planes rather than the connector, and cathode doesn't change it
+                rotation: None,
+            });
+        }
+        Ok(outputs)
+    }
+
+    fn new_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        if verbose {
+            say!("Mode {} will be passed to the kernel directly when it is set on display {}.", mode.get_name(), display);
+        }
+        Ok(())
+    }
+
+    fn check_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        if verbose {
+            say!("Validating mode {} on display {} with a test-only commit.", mode.get_name(), display);
+        }
+        self.commit(&[(modeinfo(mode)?, display)], true, verbose)
+    }
+
+    fn switch_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        if verbose {
+            say!("Applying mode {} to display {}",mode.get_name(),display);
+        }
+        self.commit(&[(modeinfo(mode)?, display)], false, verbose)?;
+        if verbose {
+            say!("Successfully applied mode {} to display {}",mode.get_name(),display);
+        }
+        Ok(())
+    }
+
+    fn set_output_mode(&mut self, mode: &OutputMode, display: &str, verbose: bool) -> Result<(), Error> {
+        let m = self.existing_mode(mode, display, verbose)?;
+        if verbose {
+            say!("Applying mode {} to display {}",mode.name,display);
+        }
+        self.commit(&[(m, display)], false, verbose)
+    }
+
+    // Every display changes in the same atomic commit, so either all of them switch or none do
+    fn switch_modes(&mut self, changes: &[(&str, ModeRef)], verbose: bool) -> Result<(), Error> {
+        let mut modes: Vec<(control::Mode, &str)> = Vec::with_capacity(changes.len());
+        for (display, mode) in changes {
+            let m = match mode {
+                ModeRef::Custom(m) => modeinfo(m)?,
+                ModeRef::Output(m) => self.existing_mode(m, display, verbose)?,
+            };
+            modes.push((m, *display));
+        }
+        if verbose {
+            let list = changes.iter().map(|(d, m)| format!("{} on display {}", m.name(), d)).collect::<Vec<_>>().join(", ");
+            say!("Applying mode {}", list);
+        }
+        self.commit(&modes, false, verbose)
+    }
+
+    // Doesn't need DRM master, so this works alongside a display server too
+    fn vblanks(&mut self, display: &str, count: u32, verbose: bool) -> Result<Vec<Vblank>, Error> {
+        let card = self.open(verbose)?;
+        let (_, info) = find_connector(card, display)?;
+        let crtc = current_crtc(card, &info).ok_or_else(|| Error::Unsupported(format!("Display {} is switched off.", display)))?;
+        // the vblank ioctl identifies CRTCs by their index rather than their handle
+        let pipe = card.resource_handles()?.crtcs().iter().position(|c| *c == crtc).unwrap_or(0) as u32;
+        let wait = drm_ffi::drm_vblank_seq_type::_DRM_VBLANK_RELATIVE
+            | ((pipe << drm_ffi::_DRM_VBLANK_HIGH_CRTC_SHIFT) & drm_ffi::drm_vblank_seq_type::_DRM_VBLANK_HIGH_CRTC_MASK);
+        (0..count).map(|_| {
+            let reply = drm_ffi::wait_vblank(card.as_fd(), wait, 1, 0)
+                .map_err(|e| Error::backend(format!("Could not wait for a vblank on display {}: {}", display, e)))?;
+            Ok(Vblank { sequence: u64::from(reply.sequence), usec: reply.tval_sec as u64 * 1_000_000 + reply.tval_usec as u64 })
+        }).collect()
+    }
+}
+
+
+struct Card {
+    file: fs::File,
+    path: path::PathBuf,
+}
+
+impl AsFd for Card {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl Device for Card {}
+impl ControlDevice for Card {}
+
+impl Card {
+    fn open(p: &path::Path) -> Result<Card, Error> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(p)
+            .map_err(|e| Error::Io(io::Error::new(e.kind(), format!("Could not open {}: {}", p.display(), e))))?;
+        let card = Card { file, path: p.to_path_buf() };
+        card.set_client_capability(ClientCapability::UniversalPlanes, true)?;
+        card.set_client_capability(ClientCapability::Atomic, true)
+            .map_err(|_| Error::Unsupported(format!("{} does not support atomic mode setting.", p.display())))?;
+        Ok(card)
+    }
+
+    // Opens the first /dev/dri/card* with a connected display, or failing that the first one that opens at all
+    fn find() -> Result<Card, Error> {
+        let mut paths: Vec<path::PathBuf> = fs::read_dir("/dev/dri")?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name().and_then(|n| n.to_str()).map(|n| n.starts_with("card")).unwrap_or(false))
+            .collect();
+        paths.sort();
+        let mut fallback: Option<Card> = None;
+        for p in paths {
+            let card = match Card::open(&p) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let connected = card.resource_handles().map(|res| res.connectors().iter().any(|c| {
+                card.get_connector(*c, false).map(|i| i.state() == connector::State::Connected).unwrap_or(false)
+            })).unwrap_or(false);
+            if connected {
+                return Ok(card);
+            }
+            if fallback.is_none() {
+                fallback = Some(card);
+            }
+        }
+        fallback.ok_or_else(|| Error::Unsupported(String::from("No usable DRM device found in /dev/dri.")))
+    }
+}
+
+
+// Connector names follow the kernel's, e.g. DP-1 or HDMI-A-1, as used by video= and sysfs
+fn connector_name(info: &connector::Info) -> String {
+    format!("{}-{}", info.interface().as_str(), info.interface_id())
+}
+
+fn find_connector(card: &Card, display: &str) -> Result<(connector::Handle, connector::Info), Error> {
+    let res = card.resource_handles()?;
+    for handle in res.connectors() {
+        let info = card.get_connector(*handle, false)?;
+        if connector_name(&info) == display {
+            return Ok((*handle, info));
+        }
+    }
+    Err(Error::DisplayNotFound(format!("Display {} is not connected.", display)))
+}
+
+fn current_crtc(card: &Card, info: &connector::Info) -> Option<crtc::Handle> {
+    info.current_encoder()
+        .and_then(|e| card.get_encoder(e).ok())
+        .and_then(|e| e.crtc())
+}
+
+// Uses the CRTC already driving the connector, or the first one any of its encoders can use
+// that isn't `taken` by another display in the same commit
+fn pick_crtc(card: &Card, res: &control::ResourceHandles, info: &connector::Info, taken: &[crtc::Handle]) -> Result<crtc::Handle, Error> {
+    if let Some(c) = current_crtc(card, info).filter(|c| !taken.contains(c)) {
+        return Ok(c);
+    }
+    info.encoders().iter()
+        .filter_map(|e| card.get_encoder(*e).ok())
+        .flat_map(|e| res.filter_crtcs(e.possible_crtcs()))
+        .find(|c| !taken.contains(c))
+        .ok_or_else(|| Error::backend(format!("No CRTC available for display {}.", connector_name(info))))
+}
+
+fn primary_plane(card: &Card, res: &control::ResourceHandles, crtc: crtc::Handle) -> Result<plane::Handle, Error> {
+    for p in card.plane_handles()? {
+        let info = card.get_plane(p)?;
+        if !res.filter_crtcs(info.possible_crtcs()).contains(&crtc) {
+            continue;
+        }
+        let props = card.get_properties(p)?;
+        for (id, value) in props.iter() {
+            if let Ok(prop) = card.get_property(*id) {
+                if prop.name().to_str() == Ok("type") && *value == control::PlaneType::Primary as u64 {
+                    return Ok(p);
+                }
+            }
+        }
+    }
+    Err(Error::backend("No primary plane available for the CRTC."))
+}
+
+fn property_handles<T: control::ResourceHandle>(card: &Card, handle: T, names: &[&str]) -> Result<Vec<property::Handle>, Error> {
+    let props = card.get_properties(handle)?.as_hashmap(card)?;
+    names.iter().map(|n| {
+        props.get(*n).map(|p| p.handle()).ok_or_else(|| Error::Unsupported(format!("The driver does not expose the {} property.", n)))
+    }).collect()
+}
+
+fn edid_blob(card: &Card, handle: connector::Handle) -> Option<Vec<u8>> {
+    let props = card.get_properties(handle).ok()?;
+    for (id, value) in props.iter() {
+        let prop = card.get_property(*id).ok()?;
+        if prop.name().to_str() == Ok("EDID") && *value != 0 {
+            return card.get_property_blob(*value).ok();
+        }
+    }
+    None
+}
+
+// Lights up each display with its mode on a black framebuffer, all in a single atomic commit,
+// or with test_only only asks the kernel whether it would accept them. Returns the framebuffer
+// of each CRTC to keep alive if the modes were actually set.
+fn commit(card: &Card, changes: &[(control::Mode, &str)], test_only: bool) -> Result<Vec<(crtc::Handle, framebuffer::Handle, DumbBuffer)>, Error> {
+    let res = card.resource_handles()?;
+    let mut req = atomic::AtomicModeReq::new();
+    let mut buffers: Vec<(crtc::Handle, framebuffer::Handle, DumbBuffer)> = Vec::new();
+    let mut blobs: Vec<property::Value> = Vec::new();
+    let added = add_changes(card, &res, changes, &mut req, &mut buffers, &mut blobs);
+    let result = match added {
+        Ok(()) => {
+            let mut flags = AtomicCommitFlags::ALLOW_MODESET;
+            if test_only {
+                flags |= AtomicCommitFlags::TEST_ONLY;
+            }
+            card.atomic_commit(flags, req).map_err(|e| {
+                let displays = changes.iter().map(|(m, d)| format!("{} on display {}", m.name().to_str().unwrap_or(""), d)).collect::<Vec<_>>().join(", ");
+                if e.kind() == ErrorKind::PermissionDenied {
+                    Error::backend(format!("Not allowed to set modes on {}; another program such as an X server or Wayland compositor may be the DRM master.", card.path.display()))
+                } else {
+                    Error::backend(format!("The kernel rejected mode {}: {}", displays, e))
+                }
+            })
+        }
+        Err(e) => Err(e),
+    };
+    for blob in blobs {
+        if let property::Value::Blob(id) = blob {
+            let _ = card.destroy_property_blob(id);
+        }
+    }
+    if test_only || result.is_err() {
+        for (_, fb, db) in buffers.drain(..) {
+            let _ = card.destroy_framebuffer(fb);
+            let _ = card.destroy_dumb_buffer(db);
+        }
+    }
+    result.map(|()| buffers)
+}
+
+// Adds the properties which set each display's mode to the request. The framebuffers and mode
+// blobs it creates are left in `buffers` and `blobs` for the caller to clean up, even on error.
+fn add_changes(card: &Card, res: &control::ResourceHandles, changes: &[(control::Mode, &str)], req: &mut atomic::AtomicModeReq,
+               buffers: &mut Vec<(crtc::Handle, framebuffer::Handle, DumbBuffer)>, blobs: &mut Vec<property::Value>) -> Result<(), Error> {
+    for (mode, display) in changes {
+        let (conn, info) = find_connector(card, display)?;
+        let taken: Vec<crtc::Handle> = buffers.iter().map(|(c, _, _)| *c).collect();
+        let crtc = pick_crtc(card, res, &info, &taken)?;
+        let plane = primary_plane(card, res, crtc)?;
+        let conn_props = property_handles(card, conn, &["CRTC_ID"])?;
+        let crtc_props = property_handles(card, crtc, &["MODE_ID", "ACTIVE"])?;
+        let plane_props = property_handles(card, plane, &["FB_ID", "CRTC_ID", "SRC_X", "SRC_Y", "SRC_W", "SRC_H", "CRTC_X", "CRTC_Y", "CRTC_W", "CRTC_H"])?;
+        let (w, h) = mode.size();
+        // dumb buffers come zeroed, so this scans out black
+        let db = card.create_dumb_buffer((u32::from(w), u32::from(h)), DrmFourcc::Xrgb8888, 32)?;
+        let fb = match card.add_framebuffer(&db, 24, 32) {
+            Ok(fb) => fb,
+            Err(e) => {
+                let _ = card.destroy_dumb_buffer(db);
+                return Err(e.into());
+            }
+        };
+        buffers.push((crtc, fb, db));
+        let blob = card.create_property_blob(mode)?;
+        blobs.push(blob);
+        req.add_property(conn, conn_props[0], property::Value::CRTC(Some(crtc)));
+        req.add_property(crtc, crtc_props[0], blob);
+        req.add_property(crtc, crtc_props[1], property::Value::Boolean(true));
+        req.add_property(plane, plane_props[0], property::Value::Framebuffer(Some(fb)));
+        req.add_property(plane, plane_props[1], property::Value::CRTC(Some(crtc)));
+        req.add_property(plane, plane_props[2], property::Value::UnsignedRange(0));
+        req.add_property(plane, plane_props[3], property::Value::UnsignedRange(0));
+        req.add_property(plane, plane_props[4], property::Value::UnsignedRange(u64::from(w) << 16));
+        req.add_property(plane, plane_props[5], property::Value::UnsignedRange(u64::from(h) << 16));
+        req.add_property(plane, plane_props[6], property::Value::SignedRange(0));
+        req.add_property(plane, plane_props[7], property::Value::SignedRange(0));
+        req.add_property(plane, plane_props[8], property::Value::UnsignedRange(u64::from(w)));
+        req.add_property(plane, plane_props[9], property::Value::UnsignedRange(u64::from(h)));
+    }
+    Ok(())
+}
+
+fn output_mode(m: &control::Mode) -> OutputMode {
+    let (w, h) = m.size();
+    let (_, _, h_total) = m.hsync();
+    let (_, _, v_total) = m.vsync();
+    let rate = if h_total == 0 || v_total == 0 {
+        f64::from(m.vrefresh())
+    } else {
+        f64::from(m.clock()) * 1000.0 / (f64::from(h_total) * f64::from(v_total))
+    };
+    OutputMode {
+        name: m.name().to_str().unwrap_or("").to_string(),
+        width: u32::from(w),
+        height: u32::from(h),
+        rate,
+    }
+}
+
+// Reads the timings of a mode the kernel reports, with its sync and scan flags named as in a modeline
+fn mode_timings(m: &control::Mode) -> Timings {
+    let (w, h) = m.size();
+    let (hs, he, ht) = m.hsync();
+    let (vs, ve, vt) = m.vsync();
+    let names = [
+        (control::ModeFlags::PHSYNC, "+hsync"),
+        (control::ModeFlags::NHSYNC, "-hsync"),
+        (control::ModeFlags::PVSYNC, "+vsync"),
+        (control::ModeFlags::NVSYNC, "-vsync"),
+        (control::ModeFlags::INTERLACE, "interlace"),
+        (control::ModeFlags::DBLSCAN, "doublescan"),
+    ];
+    Timings {
+        clock: f64::from(m.clock()) / 1000.0,
+        h_disp: u32::from(w),
+        h_sync_start: u32::from(hs),
+        h_sync_end: u32::from(he),
+        h_total: u32::from(ht),
+        v_disp: u32::from(h),
+        v_sync_start: u32::from(vs),
+        v_sync_end: u32::from(ve),
+        v_total: u32::from(vt),
+        flags: names.iter().filter(|(f, _)| m.flags().contains(*f)).map(|(_, n)| n.to_string()).collect(),
+    }
+}
+
+// Converts cathode's modeline into the kernel's mode description, marked as user defined. The
+// kernel counts refresh rates in whole Hz, so vrefresh is rounded.
+fn modeinfo(mode: &CvtMode) -> Result<control::Mode, Error> {
+    let t = mode.timings()?;
+    let field = |name: &str, value: u32| -> Result<u16, Error> {
+        u16::try_from(value).map_err(|_| Error::InvalidTiming(format!("Mode {} has an invalid {}.", mode.get_name(), name)))
+    };
+    let mut flags = 0;
+    for f in &t.flags {
+        flags |= match f.to_lowercase().as_str() {
+            "+hsync" => drm_ffi::DRM_MODE_FLAG_PHSYNC,
+            "-hsync" => drm_ffi::DRM_MODE_FLAG_NHSYNC,
+            "+vsync" => drm_ffi::DRM_MODE_FLAG_PVSYNC,
+            "-vsync" => drm_ffi::DRM_MODE_FLAG_NVSYNC,
+            "interlace" => drm_ffi::DRM_MODE_FLAG_INTERLACE,
+            "doublescan" => drm_ffi::DRM_MODE_FLAG_DBLSCAN,
+            _ => 0,
+        };
+    }
+    let mut info = drm_ffi::drm_mode_modeinfo {
+        clock: (t.clock * 1000.0).round() as u32,
+        hdisplay: field("h_disp", t.h_disp)?,
+        hsync_start: field("h_sync_start", t.h_sync_start)?,
+        hsync_end: field("h_sync_end", t.h_sync_end)?,
+        htotal: field("h_total", t.h_total)?,
+        vdisplay: field("v_disp", t.v_disp)?,
+        vsync_start: field("v_sync_start", t.v_sync_start)?,
+        vsync_end: field("v_sync_end", t.v_sync_end)?,
+        vtotal: field("v_total", t.v_total)?,
+        vrefresh: mode.refresh_rate().round() as u32,
+        flags,
+        type_: drm_ffi::DRM_MODE_TYPE_USERDEF,
+        ..Default::default()
+    };
+    let name = CString::new(mode.get_name()).unwrap_or_default();
+    for (dst, src) in info.name.iter_mut().zip(name.as_bytes().iter().take(31)) {
+        *dst = *src as _;
+    }
+    Ok(control::Mode::from(info))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs the vkms virtual driver (`modprobe vkms`) and permission to become DRM master:
+    //     cargo test vkms -- --ignored
+    #[test]
+    #[ignore]
+    fn vkms_test_only_commit() {
+        let card = fs::read_dir("/dev/dri").expect("no /dev/dri")
+            .filter_map(|e| e.ok())
+            .filter_map(|e| Card::open(&e.path()).ok())
+            .find(|c| c.get_driver().map(|d| d.name() == "vkms").unwrap_or(false))
+            .expect("vkms is not loaded");
+        let path = card.path.to_str().unwrap().to_string();
+        let mut backend = Drm::new(Some(&path));
+        let outputs = backend.outputs(false).unwrap();
+        assert!(!outputs.is_empty());
+        let display = outputs[0].display.clone();
+        // cvt 1024 768 60
+        let mode = CvtMode {
+            name: String::from("1024x768_60.00"),
+            clock: String::from("63.50"),
+            h_disp: String::from("1024"),
+            h_sync_start: String::from("1072"),
+            h_sync_end: String::from("1176"),
+            h_total: String::from("1328"),
+            v_disp: String::from("768"),
+            v_sync_start: String::from("771"),
+            v_sync_end: String::from("775"),
+            v_total: String::from("798"),
+            flags: vec![String::from("-hsync"), String::from("+vsync")],
+            monitor: None,
+        };
+        backend.check_mode(&mode, &display, false).unwrap();
+        backend.switch_mode(&mode, &display, false).unwrap();
+        let current = backend.outputs(false).unwrap().into_iter().find(|o| o.display == display).unwrap().current.unwrap();
+        assert_eq!((current.width, current.height), (1024, 768));
+    }
+}
@@ -0,0 +1,172 @@
+use std::env;
+use std::result::Result;
+use crate::{edid,mode};
+use crate::error::Error;
+
+pub mod drm;
+pub mod wlr;
+pub(crate) mod x11;
+pub mod xrandr;
+
+
+/// A mode an output is running or is able to run
+#[derive(Clone,Debug)]
+pub struct OutputMode {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub rate: f64,
+}
+
+/// A connected output along with its modes and, where the backend exposes it, the monitor's EDID
+#[derive(Clone,Debug)]
+pub struct Output {
+    pub display: String,
+    pub current: Option<OutputMode>,
+    pub preferred: Option<OutputMode>,
+    pub modes: Vec<OutputMode>,
+    pub edid: Option<Vec<u8>>,
+    /// the monitor's make, model and serial number, from backends which can't read its EDID
+    pub description: Option<String>,
+}
+
+/// What an output is doing right now, as shown by `cathode status`. Backends fill in as much
+/// as they can see; disconnected outputs are included with no current mode.
+#[derive(Clone,Debug)]
+pub struct OutputStatus {
+    pub output: Output,
+    pub connected: bool,
+    /// the exact timings of the current mode
+    pub timings: Option<mode::Timings>,
+    pub position: Option<(i32, i32)>,
+    /// normal, left, inverted or right, as xrandr names them, prefixed with flipped- if mirrored
+    pub rotation: Option<String>,
+}
+
+/// A vertical blank as timestamped by the driver
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Vblank {
+    /// the display's frame counter
+    pub sequence: u64,
+    /// microseconds on the driver's monotonic clock
+    pub usec: u64,
+}
+
+/// A mode to switch a display to: a custom one, or one the display already has
+#[derive(Clone,Copy,Debug)]
+pub enum ModeRef<'a> {
+    Custom(&'a mode::CvtMode),
+    Output(&'a OutputMode),
+}
+
+impl<'a> ModeRef<'a> {
+    pub fn name(&self) -> &'a str {
+        match self {
+            ModeRef::Custom(m) => m.get_name(),
+            ModeRef::Output(m) => &m.name,
+        }
+    }
+}
+
+impl Output {
+    pub fn identity(&self) -> Option<edid::MonitorId> {
+        self.edid.as_ref().and_then(|e| edid::parse_identity(e))
+    }
+
+    /// The mode to go back to after testing another: the current one, or the preferred one if
+    /// the display is switched off
+    pub fn revert_mode(&self) -> Result<&OutputMode, Error> {
+        self.current.as_ref().or(self.preferred.as_ref())
+            .ok_or_else(|| Error::InvalidInput(format!("Display {} has no current or preferred mode to revert to after the test.", self.display)))
+    }
+}
+
+/// A way of listing displays and setting their modes. Get one for the running session with [`detect`].
+pub trait DisplayBackend {
+    fn name(&self) -> &'static str;
+    /// Retrieves every connected output
+    fn outputs(&mut self, verbose: bool) -> Result<Vec<Output>, Error>;
+    /// Retrieves every output the backend knows of, connected or not, along with its current state
+    fn status(&mut self, verbose: bool) -> Result<Vec<OutputStatus>, Error> {
+        Ok(self.outputs(verbose)?.into_iter().map(|output| OutputStatus {
+            output,
+            connected: true,
+            timings: None,
+            position: None,
+            rotation: None,
+        }).collect())
+    }
+    /// Makes a custom mode available on the display
+    fn new_mode(&mut self, mode: &mode::CvtMode, display: &str, verbose: bool) -> Result<(), Error>;
+    /// Asks the backend whether the display would accept the mode without changing anything.
+    /// Backends with no way to validate a mode accept everything.
+    fn check_mode(&mut self, _mode: &mode::CvtMode, _display: &str, _verbose: bool) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Takes a custom mode passed to new_mode off the display again, and forgets it. Backends
+    /// which don't keep custom modes have nothing to remove.
+    fn remove_mode(&mut self, _mode: &mode::CvtMode, _display: &str, _verbose: bool) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Switches the display to a custom mode previously passed to new_mode
+    fn switch_mode(&mut self, mode: &mode::CvtMode, display: &str, verbose: bool) -> Result<(), Error>;
+    /// Switches the display to one of its existing modes, e.g. to revert after a test
+    fn set_output_mode(&mut self, mode: &OutputMode, display: &str, verbose: bool) -> Result<(), Error>;
+    /// Switches several displays at once. Backends which can change them all in one request
+    /// do, so the layout doesn't pass through intermediate states; the rest switch them in turn.
+    fn switch_modes(&mut self, changes: &[(&str, ModeRef)], verbose: bool) -> Result<(), Error> {
+        for (display, mode) in changes {
+            match mode {
+                ModeRef::Custom(m) => self.switch_mode(m, display, verbose)?,
+                ModeRef::Output(m) => self.set_output_mode(m, display, verbose)?,
+            }
+        }
+        Ok(())
+    }
+    /// Waits for the display's next `count` vertical blanks and returns when each happened,
+    /// to measure the rate it really runs at
+    fn vblanks(&mut self, _display: &str, _count: u32, _verbose: bool) -> Result<Vec<Vblank>, Error> {
+        Err(Error::Unsupported(format!("The {} backend cannot measure vblank timing.", self.name())))
+    }
+}
+
+/// Picks the backend named by `spec` (xrandr, wlr, drm or drm:<device>), or without one
+/// the wlr-output-management backend under Wayland, xrandr under X and DRM otherwise
+pub fn detect(spec: Option<&str>, verbose: bool) -> Result<Box<dyn DisplayBackend>, Error> {
+    let backend: Box<dyn DisplayBackend> = match spec {
+        Some("xrandr") => Box::new(xrandr::Xrandr::new()),
+        Some("wlr") => Box::new(wlr::Wlr::new()),
+        Some("drm") => Box::new(drm::Drm::new(None)),
+        Some(s) if s.starts_with("drm:") => Box::new(drm::Drm::new(Some(&s[4..]))),
+        Some(s) => return Err(check_spec(s).unwrap_err()),
+        None if env::var_os("WAYLAND_DISPLAY").is_some() => Box::new(wlr::Wlr::new()),
+        None if env::var_os("DISPLAY").is_some() => Box::new(xrandr::Xrandr::new()),
+        None => Box::new(drm::Drm::new(None)),
+    };
+    if verbose {
+        say!("Using the {} backend.", backend.name());
+    }
+    Ok(backend)
+}
+
+pub fn check_spec(spec: &str) -> Result<(), Error> {
+    match spec {
+        "xrandr" | "wlr" | "drm" => Ok(()),
+        s if s.starts_with("drm:") => Ok(()),
+        s => Err(Error::InvalidInput(format!("Unknown backend {}; expected xrandr, wlr, drm or drm:<device>.", s))),
+    }
+}
+
+// Resolves a connector name or monitor identity to the output it refers to
+pub fn find_output<'a>(spec: &str, outputs: &'a [Output]) -> Option<&'a Output> {
+    if let Some(o) = outputs.iter().find(|o| o.display == spec) {
+        return Some(o);
+    }
+    let id: edid::MonitorId = spec.parse().ok()?;
+    find_monitor(&id, outputs)
+}
+
+// Finds the connector a monitor is currently plugged into
+pub fn find_monitor<'a>(id: &edid::MonitorId, outputs: &'a [Output]) -> Option<&'a Output> {
+    outputs.iter().find(|o| o.identity().as_ref() == Some(id))
+}
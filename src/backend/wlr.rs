@@ -0,0 +1,405 @@
+use std::result::Result;
+use wayland_client::{Connection,Dispatch,EventQueue,QueueHandle,WEnum,event_created_child};
+use wayland_client::globals::{registry_queue_init,GlobalListContents};
+use wayland_client::protocol::{wl_output,wl_registry};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1,
+    zwlr_output_configuration_v1::{self,ZwlrOutputConfigurationV1},
+    zwlr_output_head_v1::{self,ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self,ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self,ZwlrOutputModeV1},
+};
+use crate::command;
+use crate::backend::{DisplayBackend,Output,OutputMode,OutputStatus};
+use crate::error::Error;
+use crate::mode::CvtMode;
+
+
+// Drives wlroots-based compositors (sway, Hyprland, river, ...) through the
+// zwlr_output_manager_v1 protocol. Custom modes are passed straight to the compositor
+// with set_custom_mode, so there is nothing to register beforehand.
+#[derive(Default)]
+pub struct Wlr {
+    // whether the user has been told that monitors can't be identified by their EDID
+    warned: bool,
+}
+
+impl Wlr {
+    pub fn new() -> Wlr {
+        Wlr::default()
+    }
+
+    // The protocol only exposes the make, model and serial number, which tell profiles apart
+    // but don't give a monitor identity that modes can be saved for
+    fn warn_no_identity(&mut self) {
+        if !self.warned {
+            warn!("Warning: the compositor does not expose EDIDs, so monitors are recognised by make, model and serial number, and modes can't follow a monitor to another connector.");
+            self.warned = true;
+        }
+    }
+}
+
+impl DisplayBackend for Wlr {
+    fn name(&self) -> &'static str {
+        "wlr-output-management"
+    }
+
+    fn outputs(&mut self, verbose: bool) -> Result<Vec<Output>, Error> {
+        if verbose {
+            say!("Retrieving current output configuration from the compositor.");
+        }
+        let session = Session::connect()?;
+        self.warn_no_identity();
+        Ok(session.state.heads.iter().map(|h| session.state.output(h)).collect())
+    }
+
+    // The compositor only lists heads which are connected; disabled ones have no mode or position
+    fn status(&mut self, verbose: bool) -> Result<Vec<OutputStatus>, Error> {
+        if verbose {
+            say!("Retrieving current output configuration from the compositor.");
+        }
+        let session = Session::connect()?;
+        self.warn_no_identity();
+        Ok(session.state.heads.iter().map(|h| OutputStatus {
+            output: session.state.output(h),
+            connected: true,
+            timings: None,
+            position: if h.enabled { Some(h.position) } else { None },
+            rotation: if h.enabled { h.transform.map(rotation) } else { None },
+        }).collect())
+    }
+
+    fn new_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        // custom modes are only a size and refresh rate, so scanning flags can't be passed on
+        let t = mode.timings()?;
+        if t.has_flag("interlace") || t.has_flag("doublescan") {
+            return Err(Error::Unsupported(format!("wlroots compositors cannot set interlaced or doublescan modes such as {}.", mode.get_name())));
+        }
+        if verbose {
+            say!("Mode {} will be set on display {} as a custom mode.", mode.get_name(), display);
+        }
+        Ok(())
+    }
+
+    fn check_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        let mut session = Session::connect()?;
+        let request = session.state.request_for(mode, display);
+        if verbose {
+            say!("Asking the compositor to validate mode {} on display {}.", mode.get_name(), display);
+        }
+        session.configure(display, request, true)
+    }
+
+    fn switch_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        let mut session = Session::connect()?;
+        let request = session.state.request_for(mode, display);
+        if verbose {
+            say!("Applying mode {} to display {}",mode.get_name(),display);
+        }
+        session.configure(display, request, false)?;
+        if verbose {
+            say!("Successfully applied mode {} to display {}",mode.get_name(),display);
+        }
+        Ok(())
+    }
+
+    fn set_output_mode(&mut self, mode: &OutputMode, display: &str, verbose: bool) -> Result<(), Error> {
+        let mut session = Session::connect()?;
+        let m = session.state.find_mode(display, mode.width as i32, mode.height as i32, mode.rate, f64::MAX)
+            .ok_or_else(|| Error::ModeNotFound(format!("Display {} has no mode {}.", display, mode.name)))?;
+        if verbose {
+            say!("Applying mode {} to display {}",mode.name,display);
+        }
+        session.configure(display, ModeRequest::Existing(m), false)?;
+        if verbose {
+            say!("Successfully applied mode {} to display {}",mode.name,display);
+        }
+        Ok(())
+    }
+}
+
+
+enum ModeRequest {
+    Existing(ZwlrOutputModeV1),
+    Custom(i32, i32, i32),
+}
+
+struct Head {
+    proxy: ZwlrOutputHeadV1,
+    name: String,
+    enabled: bool,
+    modes: Vec<ZwlrOutputModeV1>,
+    current: Option<ZwlrOutputModeV1>,
+    position: (i32, i32),
+    transform: Option<wl_output::Transform>,
+    scale: f64,
+    make: Option<String>,
+    model: Option<String>,
+    serial_number: Option<String>,
+}
+
+impl Head {
+    // None if the compositor sent none of the three
+    fn description(&self) -> Option<String> {
+        let parts: Vec<&str> = [&self.make, &self.model, &self.serial_number].iter()
+            .filter_map(|p| p.as_deref())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if parts.is_empty() { None } else { Some(parts.join(" ")) }
+    }
+}
+
+struct Mode {
+    proxy: ZwlrOutputModeV1,
+    width: i32,
+    height: i32,
+    // vertical refresh rate in mHz
+    refresh: i32,
+    preferred: bool,
+}
+
+enum ConfigResult {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Default)]
+struct State {
+    heads: Vec<Head>,
+    modes: Vec<Mode>,
+    serial: Option<u32>,
+    result: Option<ConfigResult>,
+}
+
+impl State {
+    fn mode(&self, proxy: &ZwlrOutputModeV1) -> Option<&Mode> {
+        self.modes.iter().find(|m| m.proxy == *proxy)
+    }
+
+    fn output_mode(&self, proxy: &ZwlrOutputModeV1) -> Option<OutputMode> {
+        self.mode(proxy).map(|m| OutputMode {
+            name: format!("{}x{}", m.width, m.height),
+            width: m.width as u32,
+            height: m.height as u32,
+            rate: f64::from(m.refresh) / 1000.0,
+        })
+    }
+
+    fn output(&self, head: &Head) -> Output {
+        Output {
+            display: head.name.clone(),
+            current: head.current.as_ref().and_then(|m| self.output_mode(m)),
+            preferred: head.modes.iter().find(|m| self.mode(m).map(|m| m.preferred).unwrap_or(false)).and_then(|m| self.output_mode(m)),
+            modes: head.modes.iter().filter_map(|m| self.output_mode(m)).collect(),
+            // the protocol only exposes make, model and serial, not the EDID itself
+            edid: None,
+            description: head.description(),
+        }
+    }
+
+    // Prefers a mode the output already advertises, since the compositor may not accept custom timings
+    fn request_for(&self, mode: &CvtMode, display: &str) -> ModeRequest {
+        let (width, height, rate) = (mode.width() as i32, mode.height() as i32, mode.refresh_rate());
+        match self.find_mode(display, width, height, rate, 0.01) {
+            Some(m) => ModeRequest::Existing(m),
+            None => ModeRequest::Custom(width, height, (rate * 1000.0).round() as i32),
+        }
+    }
+
+    // Finds the advertised mode of the given size whose refresh rate is closest to `rate`, within `tolerance` Hz
+    fn find_mode(&self, display: &str, width: i32, height: i32, rate: f64, tolerance: f64) -> Option<ZwlrOutputModeV1> {
+        let head = self.heads.iter().find(|h| h.name == display)?;
+        head.modes.iter()
+            .filter_map(|p| self.mode(p))
+            .filter(|m| m.width == width && m.height == height)
+            .map(|m| (m, (f64::from(m.refresh) / 1000.0 - rate).abs()))
+            .filter(|(_, diff)| *diff <= tolerance)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(m, _)| m.proxy.clone())
+    }
+}
+
+struct Session {
+    queue: EventQueue<State>,
+    state: State,
+    manager: ZwlrOutputManagerV1,
+}
+
+impl Session {
+    fn connect() -> Result<Session, Error> {
+        let conn = Connection::connect_to_env().map_err(|e| Error::backend(format!("Could not connect to the Wayland compositor: {}", e)))?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(|e| Error::backend(e.to_string()))?;
+        let manager: ZwlrOutputManagerV1 = globals.bind(&queue.handle(), 1..=4, ())
+            .map_err(|_| Error::Unsupported(String::from("The compositor does not support the wlr-output-management protocol.")))?;
+        let mut state = State::default();
+        // the manager sends every head and mode, then done once the description is complete
+        while state.serial.is_none() {
+            queue.roundtrip(&mut state).map_err(|e| Error::backend(e.to_string()))?;
+        }
+        Ok(Session { queue, state, manager })
+    }
+
+    // Applies, or with test_only just validates, a configuration which changes the mode of
+    // `display` and leaves every other head as it is
+    fn configure(&mut self, display: &str, request: ModeRequest, test_only: bool) -> Result<(), Error> {
+        if !self.state.heads.iter().any(|h| h.name == display) {
+            return Err(Error::DisplayNotFound(format!("Display {} is not connected.", display)));
+        }
+        // with --dry-run the compositor is only asked whether it would accept the configuration
+        let test_only = test_only || command::dry_run();
+        let qh = self.queue.handle();
+        let config = self.manager.create_configuration(self.state.serial.unwrap(), &qh, ());
+        for head in &self.state.heads {
+            // every head has to be either enabled or disabled, or the configuration is a protocol error
+            if !head.enabled && head.name != display {
+                config.disable_head(&head.proxy);
+                continue;
+            }
+            let ch = config.enable_head(&head.proxy, &qh, ());
+            if head.name == display {
+                match &request {
+                    ModeRequest::Existing(m) => ch.set_mode(m),
+                    ModeRequest::Custom(w, h, r) => ch.set_custom_mode(*w, *h, *r),
+                }
+            } else if let Some(m) = &head.current {
+                ch.set_mode(m);
+            }
+            ch.set_position(head.position.0, head.position.1);
+            if let Some(t) = head.transform {
+                ch.set_transform(t);
+            }
+            ch.set_scale(head.scale);
+        }
+        if test_only {
+            config.test();
+        } else {
+            config.apply();
+        }
+        self.state.result = None;
+        while self.state.result.is_none() {
+            self.queue.blocking_dispatch(&mut self.state).map_err(|e| Error::backend(e.to_string()))?;
+        }
+        config.destroy();
+        match self.state.result.take() {
+            Some(ConfigResult::Succeeded) => Ok(()),
+            Some(ConfigResult::Cancelled) => Err(Error::backend("The output configuration changed while applying the mode; try again.")),
+            _ => Err(Error::backend(format!("The compositor rejected the mode for display {}.", display))),
+        }
+    }
+}
+
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(_: &mut State, _: &wl_registry::WlRegistry, _: wl_registry::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<State>) {}
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for State {
+    fn event(state: &mut State, _: &ZwlrOutputManagerV1, event: zwlr_output_manager_v1::Event, _: &(), _: &Connection, _: &QueueHandle<State>) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => state.heads.push(Head {
+                proxy: head,
+                name: String::new(),
+                enabled: false,
+                modes: Vec::new(),
+                current: None,
+                position: (0, 0),
+                transform: None,
+                scale: 1.0,
+                make: None,
+                model: None,
+                serial_number: None,
+            }),
+            zwlr_output_manager_v1::Event::Done { serial } => state.serial = Some(serial),
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for State {
+    fn event(state: &mut State, proxy: &ZwlrOutputHeadV1, event: zwlr_output_head_v1::Event, _: &(), _: &Connection, _: &QueueHandle<State>) {
+        if let zwlr_output_head_v1::Event::Mode { mode } = &event {
+            state.modes.push(Mode { proxy: mode.clone(), width: 0, height: 0, refresh: 0, preferred: false });
+        }
+        if let zwlr_output_head_v1::Event::Finished = event {
+            state.heads.retain(|h| h.proxy != *proxy);
+            return;
+        }
+        let head = match state.heads.iter_mut().find(|h| h.proxy == *proxy) {
+            Some(h) => h,
+            None => return,
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => head.name = name,
+            zwlr_output_head_v1::Event::Mode { mode } => head.modes.push(mode),
+            zwlr_output_head_v1::Event::Enabled { enabled } => head.enabled = enabled != 0,
+            zwlr_output_head_v1::Event::CurrentMode { mode } => head.current = Some(mode),
+            zwlr_output_head_v1::Event::Position { x, y } => head.position = (x, y),
+            zwlr_output_head_v1::Event::Transform { transform: WEnum::Value(t) } => head.transform = Some(t),
+            zwlr_output_head_v1::Event::Scale { scale } => head.scale = scale,
+            zwlr_output_head_v1::Event::Make { make } => head.make = Some(make),
+            zwlr_output_head_v1::Event::Model { model } => head.model = Some(model),
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => head.serial_number = Some(serial_number),
+            _ => {}
+        }
+    }
+
+    event_created_child!(State, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputModeV1, ()> for State {
+    fn event(state: &mut State, proxy: &ZwlrOutputModeV1, event: zwlr_output_mode_v1::Event, _: &(), _: &Connection, _: &QueueHandle<State>) {
+        if let zwlr_output_mode_v1::Event::Finished = event {
+            state.modes.retain(|m| m.proxy != *proxy);
+            return;
+        }
+        let mode = match state.modes.iter_mut().find(|m| m.proxy == *proxy) {
+            Some(m) => m,
+            None => return,
+        };
+        match event {
+            zwlr_output_mode_v1::Event::Size { width, height } => {
+                mode.width = width;
+                mode.height = height;
+            }
+            zwlr_output_mode_v1::Event::Refresh { refresh } => mode.refresh = refresh,
+            zwlr_output_mode_v1::Event::Preferred => mode.preferred = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationV1, ()> for State {
+    fn event(state: &mut State, _: &ZwlrOutputConfigurationV1, event: zwlr_output_configuration_v1::Event, _: &(), _: &Connection, _: &QueueHandle<State>) {
+        state.result = match event {
+            zwlr_output_configuration_v1::Event::Succeeded => Some(ConfigResult::Succeeded),
+            zwlr_output_configuration_v1::Event::Failed => Some(ConfigResult::Failed),
+            zwlr_output_configuration_v1::Event::Cancelled => Some(ConfigResult::Cancelled),
+            _ => return,
+        };
+    }
+}
+
+impl Dispatch<ZwlrOutputConfigurationHeadV1, ()> for State {
+    fn event(_: &mut State, _: &ZwlrOutputConfigurationHeadV1, _: <ZwlrOutputConfigurationHeadV1 as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<State>) {}
+}
+
+// Wayland transforms turn the output counter-clockwise, like xrandr's left
+fn rotation(t: wl_output::Transform) -> String {
+    String::from(match t {
+        wl_output::Transform::_90 => "left",
+        wl_output::Transform::_180 => "inverted",
+        wl_output::Transform::_270 => "right",
+        wl_output::Transform::Flipped => "flipped-normal",
+        wl_output::Transform::Flipped90 => "flipped-left",
+        wl_output::Transform::Flipped180 => "flipped-inverted",
+        wl_output::Transform::Flipped270 => "flipped-right",
+        _ => "normal",
+    })
+}
@@ -0,0 +1,85 @@
+// Talks to the X server directly, for what the xrandr tool can't do
+
+use std::fmt;
+use std::result::Result;
+use x11rb::connection::{Connection,RequestConnection};
+use x11rb::protocol::Event;
+use x11rb::protocol::present::{self,CompleteKind,ConnectionExt as _};
+use x11rb::protocol::randr::{self,ConnectionExt as _};
+use x11rb::protocol::xproto::{ConnectionExt as _,CreateWindowAux,Window,WindowClass};
+use x11rb::rust_connection::RustConnection;
+use x11rb::COPY_DEPTH_FROM_PARENT;
+use crate::backend::Vblank;
+use crate::error::Error;
+
+
+pub(crate) fn x_error<E: fmt::Display>(e: E) -> Error {
+    Error::backend(format!("X request failed: {}", e))
+}
+
+// Connects to $DISPLAY and checks the server has the Present extension, returning the default screen
+pub(crate) fn connect() -> Result<(RustConnection, usize), Error> {
+    let (conn, screen) = x11rb::connect(None).map_err(|e| Error::backend(format!("Could not connect to the X server: {}", e)))?;
+    if conn.extension_information(present::X11_EXTENSION_NAME).map_err(x_error)?.is_none() {
+        return Err(Error::Unsupported(String::from("The X server does not support the Present extension.")));
+    }
+    conn.present_query_version(1, 0).map_err(x_error)?.reply().map_err(x_error)?;
+    Ok((conn, screen))
+}
+
+// The CRTC driving the display, with its position and size on the screen
+pub(crate) fn find_crtc(conn: &RustConnection, root: Window, display: &str) -> Result<(randr::Crtc, randr::GetCrtcInfoReply), Error> {
+    let res = conn.randr_get_screen_resources_current(root).map_err(x_error)?.reply().map_err(x_error)?;
+    let mut crtc = None;
+    for o in &res.outputs {
+        let info = conn.randr_get_output_info(*o, res.config_timestamp).map_err(x_error)?.reply().map_err(x_error)?;
+        if info.name == display.as_bytes() {
+            crtc = Some(info.crtc);
+        }
+    }
+    match crtc {
+        None => Err(Error::DisplayNotFound(format!("Display {} is not connected.", display))),
+        Some(0) => Err(Error::Unsupported(format!("Display {} is switched off.", display))),
+        Some(c) => Ok((c, conn.randr_get_crtc_info(c, res.config_timestamp).map_err(x_error)?.reply().map_err(x_error)?)),
+    }
+}
+
+// Times vblanks through the Present extension, which reports the frame counter and timestamp
+// of the CRTC showing a window. A 1x1 window is put in the corner of the display so that
+// CRTC is the display's, and a notification is queued for each of the frames to come.
+pub(crate) fn vblanks(display: &str, count: u32, verbose: bool) -> Result<Vec<Vblank>, Error> {
+    let (conn, screen) = connect()?;
+    let root = conn.setup().roots[screen].root;
+    let (_, crtc) = find_crtc(&conn, root, display)?;
+    if verbose {
+        say!("Waiting for {} vblanks on display {} through the Present extension.", count, display);
+    }
+    let window = conn.generate_id().map_err(x_error)?;
+    conn.create_window(COPY_DEPTH_FROM_PARENT, window, root, crtc.x, crtc.y, 1, 1, 0, WindowClass::INPUT_OUTPUT, 0,
+        &CreateWindowAux::new().override_redirect(1)).map_err(x_error)?;
+    conn.map_window(window).map_err(x_error)?;
+    let eid = conn.generate_id().map_err(x_error)?;
+    conn.present_select_input(eid, window, present::EventMask::COMPLETE_NOTIFY).map_err(x_error)?;
+    // the first notification says where the frame counter is; the rest are queued up front
+    // so none are missed while we wait for replies
+    conn.present_notify_msc(window, 0, 0, 1, 0).map_err(x_error)?;
+    conn.flush().map_err(x_error)?;
+    let mut vblanks: Vec<Vblank> = Vec::new();
+    while (vblanks.len() as u32) < count {
+        if let Event::PresentCompleteNotify(e) = conn.wait_for_event().map_err(x_error)? {
+            if e.kind != CompleteKind::NOTIFY_MSC || e.window != window {
+                continue;
+            }
+            if vblanks.is_empty() {
+                for i in 1..count {
+                    conn.present_notify_msc(window, i, e.msc + u64::from(i), 0, 0).map_err(x_error)?;
+                }
+                conn.flush().map_err(x_error)?;
+            }
+            vblanks.push(Vblank { sequence: e.msc, usec: e.ust });
+        }
+    }
+    conn.destroy_window(window).map_err(x_error)?;
+    conn.flush().map_err(x_error)?;
+    Ok(vblanks)
+}
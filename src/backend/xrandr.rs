@@ -0,0 +1,270 @@
+use std::{process,str};
+use std::result::Result;
+use regex::Regex;
+use crate::command;
+use crate::backend::{x11,DisplayBackend,ModeRef,Output,OutputMode,OutputStatus,Vblank};
+use crate::error::Error;
+use crate::mode::{CvtMode,Timings};
+
+
+// Drives the X server through the xrandr command line tool
+#[derive(Default)]
+pub struct Xrandr;
+
+impl Xrandr {
+    pub fn new() -> Xrandr {
+        Xrandr
+    }
+}
+
+impl DisplayBackend for Xrandr {
+    fn name(&self) -> &'static str {
+        "xrandr"
+    }
+
+    fn outputs(&mut self, verbose: bool) -> Result<Vec<Output>, Error> {
+        let outputs: Vec<Output> = self.status(verbose)?.into_iter()
+            .filter(|s| s.connected)
+            .map(|s| s.output)
+            .collect();
+        if verbose {
+            for o in &outputs {
+                say!("Found connected display {} running mode {} (EDID {})", o.display, o.current.as_ref().map(|m| m.name.as_str()).unwrap_or("none"), if o.edid.is_some() { "present" } else { "missing" });
+            }
+        }
+        Ok(outputs)
+    }
+
+    fn status(&mut self, verbose: bool) -> Result<Vec<OutputStatus>, Error> {
+        if verbose {
+            say!("Retrieving current display configuration.");
+        }
+        let mut cmd = process::Command::new("xrandr");
+        cmd.arg("--current").arg("--verbose");
+        let output = command::query(&mut cmd)?;
+        check(&cmd, &output, "Could not read the display configuration")?;
+        Ok(parse_status(str::from_utf8(&output.stdout).unwrap_or("")))
+    }
+
+    // Adds the newly created mode to xrandr
+    fn new_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        let mut cmd = process::Command::new("xrandr");
+        cmd.arg("--newmode")
+            .arg(&mode.name)
+            .arg(&mode.clock)
+            .arg(&mode.h_disp)
+            .arg(&mode.h_sync_start)
+            .arg(&mode.h_sync_end)
+            .arg(&mode.h_total)
+            .arg(&mode.v_disp)
+            .arg(&mode.v_sync_start)
+            .arg(&mode.v_sync_end)
+            .arg(&mode.v_total)
+            // one argument per flag; xrandr doesn't split them itself
+            .args(&mode.flags);
+        if verbose {
+            say!("Creating xrandr mode {}",&mode.name);
+        }
+        let output = command::execute(&mut cmd)?;
+        let created = check(&cmd, &output, &format!("Could not create mode {}", mode.name));
+        // a mode left over from an earlier run still has to be added to this display
+        match &created {
+            Err(Error::ModeExists(_)) => (),
+            Err(_) => return created,
+            _ => (),
+        }
+        if verbose {
+            say!("Adding mode {} for display {}.",&mode.name,display);
+        }
+        cmd = process::Command::new("xrandr");
+        cmd.arg("--addmode").arg(display).arg(&mode.name);
+        let output = command::execute(&mut cmd)?;
+        check(&cmd, &output, &format!("Could not add mode {} to display {}", mode.name, display))?;
+        created
+    }
+
+    // The mode can't be running on the display, or xrandr refuses to remove it
+    fn remove_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        if verbose {
+            say!("Removing mode {} from display {}.", &mode.name, display);
+        }
+        let mut cmd = process::Command::new("xrandr");
+        cmd.arg("--delmode").arg(display).arg(&mode.name);
+        let output = command::execute(&mut cmd)?;
+        check(&cmd, &output, &format!("Could not remove mode {} from display {}", mode.name, display))?;
+        cmd = process::Command::new("xrandr");
+        cmd.arg("--rmmode").arg(&mode.name);
+        let output = command::execute(&mut cmd)?;
+        check(&cmd, &output, &format!("Could not delete mode {}", mode.name))
+    }
+
+    fn switch_mode(&mut self, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+        self.switch_modes(&[(display, ModeRef::Custom(mode))], verbose)
+    }
+
+    fn set_output_mode(&mut self, mode: &OutputMode, display: &str, verbose: bool) -> Result<(), Error> {
+        self.switch_modes(&[(display, ModeRef::Output(mode))], verbose)
+    }
+
+    // One xrandr call with an --output for each display, so they all change together
+    fn switch_modes(&mut self, changes: &[(&str, ModeRef)], verbose: bool) -> Result<(), Error> {
+        let mut cmd = process::Command::new("xrandr");
+        for (display, mode) in changes {
+            cmd.arg("--output").arg(display).arg("--mode").arg(mode.name());
+            // several modes can share a name like 1920x1080, so pin the refresh rate as well
+            if let ModeRef::Output(m) = mode {
+                cmd.arg("--rate").arg(format!("{:.2}", m.rate));
+            }
+        }
+        let what = changes.iter().map(|(display, mode)| format!("mode {} to display {}", mode.name(), display)).collect::<Vec<_>>().join(", ");
+        if verbose {
+            say!("Applying {}",what);
+        }
+        let output = command::execute(&mut cmd)?;
+        check(&cmd, &output, &format!("Could not apply {}", what))?;
+        if verbose {
+            say!("Successfully applied {}",what);
+        }
+        Ok(())
+    }
+
+    // xrandr has no way to wait for a vblank, so this talks to the X server directly
+    fn vblanks(&mut self, display: &str, count: u32, verbose: bool) -> Result<Vec<Vblank>, Error> {
+        x11::vblanks(display, count, verbose)
+    }
+}
+
+// xrandr reports some failures, such as an unknown output, only as a warning with a zero exit
+// status, so stderr is checked as well. Known X errors are turned into a description of what
+// went wrong; anything else is reported along with xrandr's own message.
+fn check(cmd: &process::Command, output: &process::Output, action: &str) -> Result<(), Error> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let known: [(&str, &str); 7] = [
+        ("BadName", "the X server already has a mode with that name; choose another name or remove it with xrandr --rmmode"),
+        ("BadAccess", "the mode is in use"),
+        ("BadMatch", "the display does not accept the mode"),
+        ("cannot find mode", "the X server has no mode with that name"),
+        ("not found; ignoring", "the X server has no output with that name"),
+        ("Configure crtc", "the graphics card could not drive the display with the mode, which may be beyond what the card, cable or display supports"),
+        ("Can't open display", "could not connect to the X server; is $DISPLAY set?"),
+    ];
+    let known = known.iter().find(|(pattern, _)| stderr.contains(pattern));
+    if output.status.success() && known.is_none() {
+        return Ok(());
+    }
+    let command = command::command_line(cmd);
+    let said = stderr.lines().map(str::trim).filter(|l| !l.is_empty()).collect::<Vec<_>>().join("; ");
+    let (pattern, description) = match known {
+        Some((pattern, description)) => (*pattern, description.to_string()),
+        None => ("", format!("xrandr exited with {}", output.status)),
+    };
+    let reason = format!("{}: {}", action, description);
+    let message = || format!("{}. `{}` said: {}", reason, command, if said.is_empty() { "nothing" } else { &said });
+    Err(match pattern {
+        "BadName" => Error::ModeExists(message()),
+        "cannot find mode" => Error::ModeNotFound(message()),
+        "not found; ignoring" => Error::DisplayNotFound(message()),
+        _ => Error::BackendFailure { command, stderr: said, reason },
+    })
+}
+
+// Parses every output, its modes, EDID, position and rotation, and the timings of the
+// current mode out of `xrandr --current --verbose`
+fn parse_status(out: &str) -> Vec<OutputStatus> {
+    let header = Regex::new(r"^(\S+)\s+(connected|disconnected)(?:\s+primary)?(?:\s+\d+x\d+\+(-?\d+)\+(-?\d+)(?:\s+\(0x[0-9a-fA-F]+\))?\s+(normal|left|inverted|right)(\s+[XY] axis)?)?").unwrap();
+    let mode_line = Regex::new(r"^\s{2}(\S+)\s+\(0x[0-9a-fA-F]+\)\s+([0-9.]+)MHz(.*)$").unwrap();
+    let h_line = Regex::new(r"^\s+h:\s+width\s+([0-9]+)\s+start\s+([0-9]+)\s+end\s+([0-9]+)\s+total\s+([0-9]+)").unwrap();
+    let v_line = Regex::new(r"^\s+v:\s+height\s+([0-9]+)\s+start\s+([0-9]+)\s+end\s+([0-9]+)\s+total\s+([0-9]+).*clock\s+([0-9.]+)Hz").unwrap();
+    let hex_line = Regex::new(r"^\t\t([0-9a-fA-F]+)\s*$").unwrap();
+    let mut outputs: Vec<OutputStatus> = Vec::new();
+    let mut current: Option<OutputStatus> = None;
+    let mut edid_hex: Option<String> = None;
+    // name, clock, flags and horizontal timings of the mode whose h:/v: lines are being read
+    let mut pending: Option<(String, String, String, [u32; 4])> = None;
+    let number = |s: &str| s.parse::<u32>().unwrap_or(0);
+    for line in out.lines() {
+        if let Some(hex) = edid_hex.as_mut() {
+            if let Some(cap) = hex_line.captures(line) {
+                hex.push_str(&cap[1]);
+                continue;
+            }
+            if let Some(o) = current.as_mut() {
+                o.output.edid = decode_hex(hex);
+            }
+            edid_hex = None;
+        }
+        if let Some(cap) = header.captures(line) {
+            if let Some(o) = current.take() {
+                outputs.push(o);
+            }
+            let position = match (cap.get(3), cap.get(4)) {
+                (Some(x), Some(y)) => Some((x.as_str().parse().unwrap_or(0), y.as_str().parse().unwrap_or(0))),
+                _ => None,
+            };
+            let rotation = cap.get(5).map(|r| if cap.get(6).is_some() { format!("flipped-{}", r.as_str()) } else { r.as_str().to_string() });
+            current = Some(OutputStatus {
+                output: Output { display: cap[1].to_string(), current: None, preferred: None, modes: Vec::new(), edid: None, description: None },
+                connected: &cap[2] == "connected",
+                timings: None,
+                position,
+                rotation,
+            });
+            pending = None;
+        } else if let Some(o) = current.as_mut() {
+            if line.trim_start().starts_with("EDID:") {
+                edid_hex = Some(String::new());
+            } else if let Some(cap) = mode_line.captures(line) {
+                pending = Some((cap[1].to_string(), cap[2].to_string(), cap[3].to_string(), [0; 4]));
+            } else if let Some(cap) = h_line.captures(line) {
+                if let Some(p) = pending.as_mut() {
+                    p.3 = [number(&cap[1]), number(&cap[2]), number(&cap[3]), number(&cap[4])];
+                }
+            } else if let Some(cap) = v_line.captures(line) {
+                if let Some((name, clock, flags, h)) = pending.take() {
+                    let m = OutputMode {
+                        name,
+                        width: h[0],
+                        height: number(&cap[1]),
+                        rate: cap[5].parse().unwrap_or(0.0),
+                    };
+                    if flags.contains("*current") {
+                        o.output.current = Some(m.clone());
+                        o.timings = Some(Timings {
+                            clock: clock.parse().unwrap_or(0.0),
+                            h_disp: h[0],
+                            h_sync_start: h[1],
+                            h_sync_end: h[2],
+                            h_total: h[3],
+                            v_disp: number(&cap[1]),
+                            v_sync_start: number(&cap[2]),
+                            v_sync_end: number(&cap[3]),
+                            v_total: number(&cap[4]),
+                            flags: flags.split_whitespace()
+                                .filter(|f| *f != "*current" && *f != "+preferred")
+                                .map(|f| f.to_ascii_lowercase())
+                                .collect(),
+                        });
+                    }
+                    if flags.contains("+preferred") {
+                        o.output.preferred = Some(m.clone());
+                    }
+                    o.output.modes.push(m);
+                }
+            }
+        }
+    }
+    if let (Some(o), Some(hex)) = (current.as_mut(), edid_hex.as_ref()) {
+        o.output.edid = decode_hex(hex);
+    }
+    if let Some(o) = current {
+        outputs.push(o);
+    }
+    outputs
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok()).collect()
+}
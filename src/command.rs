@@ -0,0 +1,54 @@
+use std::io::ErrorKind;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command,ExitStatus,Output};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool,Ordering};
+use crate::util;
+use crate::error::Error;
+
+
+// Set once from --dry-run before any subcommand runs
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// Runs a command which only reads state, such as `xrandr --current` or cvt. These still run
+// with --dry-run, since what cathode would do next depends on their output.
+pub fn query(cmd: &mut Command) -> Result<Output, Error> {
+    if dry_run() {
+        say!("Running: {}", command_line(cmd));
+    }
+    run(cmd)
+}
+
+// Runs a command which changes the display configuration; with --dry-run it is only printed
+pub fn execute(cmd: &mut Command) -> Result<Output, Error> {
+    if dry_run() {
+        say!("Would run: {}", command_line(cmd));
+        return Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        });
+    }
+    run(cmd)
+}
+
+fn run(cmd: &mut Command) -> Result<Output, Error> {
+    cmd.output().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::MissingTool(cmd.get_program().to_string_lossy().into_owned()),
+        _ => e.into(),
+    })
+}
+
+pub fn command_line(cmd: &Command) -> String {
+    let mut words = vec![util::shell_quote(&cmd.get_program().to_string_lossy())];
+    words.extend(cmd.get_args().map(|a| util::shell_quote(&a.to_string_lossy())));
+    words.join(" ")
+}
@@ -0,0 +1,417 @@
+use std::result::Result;
+use serde::{Serialize,Deserialize};
+use serde::de::Error as _;
+use serde_yaml::{Mapping,Value};
+use serde_json::json;
+use crate::{backend,fileio,output};
+use crate::error::Error;
+use crate::mode::{CvtMode,Timing};
+use crate::profile::{Assignment,Profile};
+
+
+// The schema version written by this build. Bump it whenever the layout of the file changes
+// and add a step to `migrate` that upgrades documents of the previous version.
+pub const VERSION: u64 = 2;
+
+
+/// Everything cathode keeps in the modes file. Read and write it with [`crate::fileio::load_config`]
+/// and [`crate::fileio::update_config`].
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct Config {
+    pub(crate) version: u64,
+    #[serde(default)]
+    pub(crate) modes: Vec<CvtMode>,
+    #[serde(default)]
+    pub(crate) profiles: Vec<Profile>,
+    #[serde(default)]
+    pub(crate) persisted: Vec<Assignment>,
+    #[serde(default)]
+    pub(crate) settings: Settings,
+    // the version the file was in before it was upgraded on load, if it was
+    #[serde(skip)]
+    pub(crate) migrated_from: Option<u64>,
+}
+
+/// Defaults for options which aren't given on the command line, set with `cathode config set`
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub struct Settings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verbose: Option<bool>,
+}
+
+// Every setting along with what it controls, in the order `config list` shows them
+pub const SETTINGS: [(&str, &str); 6] = [
+    ("timeout", "seconds to test a mode for before reverting (default 10)"),
+    ("display", "display or monitor identity to add modes for (default: the first connected display)"),
+    ("timing", "timing formula for new modes: cvt or cvt-rb (default cvt)"),
+    ("backend", "xrandr, wlr, drm or drm:<device> (default: detected from the environment)"),
+    ("file", "modes file to use instead of this one (default $XDG_CONFIG_HOME/cathode/modes.yml)"),
+    ("verbose", "true to enable verbose output for every subcommand (default false)"),
+];
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            version: VERSION,
+            modes: Vec::new(),
+            profiles: Vec::new(),
+            persisted: Vec::new(),
+            settings: Settings::default(),
+            migrated_from: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parses a modes file of any schema version, upgrading it to the current one.
+    /// `legacy_profiles` is the contents of the profiles.yml that version 0 kept beside the modes file.
+    pub fn from_yaml(contents: &str, legacy_profiles: Option<&str>) -> Result<Config, serde_yaml::Error> {
+        let value: Value = if contents.trim().is_empty() {
+            Value::Null
+        } else {
+            serde_yaml::from_str(contents)?
+        };
+        let (value, migrated_from) = migrate(value, legacy_profiles)?;
+        let mut config: Config = serde_yaml::from_value(value)?;
+        config.migrated_from = migrated_from;
+        Ok(config)
+    }
+
+    /// Serializes the document in the current schema version.
+    pub fn to_yaml(&self) -> String {
+        let mut c = self.clone();
+        c.version = VERSION;
+        serde_yaml::to_string(&c).unwrap()
+    }
+
+    /// All saved modes, in the order they were added.
+    pub fn modes(&self) -> &[CvtMode] {
+        &self.modes
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn get_mode(&self, name: &str) -> Option<&CvtMode> {
+        self.modes.iter().find(|m| m.get_name() == name)
+    }
+
+    /// Saves a mode, replacing any with the same name.
+    pub fn add_mode(&mut self, mode: CvtMode) {
+        let n = mode.get_name().to_string();
+        self.modes.retain(|m| m.get_name() != n);
+        self.modes.push(mode);
+    }
+
+    pub fn add_profile(&mut self, profile: Profile) {
+        self.profiles.retain(|p| p.name != profile.name);
+        self.profiles.push(profile);
+    }
+
+    pub fn add_persisted(&mut self, assignment: Assignment) {
+        // a monitor keeps its own assignment even if another monitor was persisted on the same connector
+        self.persisted.retain(|a| match (&a.monitor, &assignment.monitor) {
+            (Some(old), Some(new)) => old != new,
+            _ => a.display != assignment.display,
+        });
+        self.persisted.push(assignment);
+    }
+}
+
+
+impl Settings {
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(match key {
+            "timeout" => self.timeout.map(|t| t.to_string()),
+            "display" => self.display.clone(),
+            "timing" => self.timing.clone(),
+            "backend" => self.backend.clone(),
+            "file" => self.file.clone(),
+            "verbose" => self.verbose.map(|v| v.to_string()),
+            _ => return Err(unknown_setting(key)),
+        })
+    }
+
+    // The setting as a JSON number, string or boolean, or null if it is unset
+    fn json_value(&self, key: &str) -> serde_json::Value {
+        serde_json::to_value(self).ok()
+            .and_then(|v| v.get(key).cloned())
+            .unwrap_or(serde_json::Value::Null)
+    }
+
+    // Sets the value of a setting, or clears it back to the default if value is None
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), Error> {
+        let invalid = |expected: &str| Error::InvalidInput(format!("Invalid value for {}; expected {}.", key, expected));
+        match key {
+            "timeout" => self.timeout = match value {
+                Some(v) => Some(v.parse().ok().filter(|t| *t > 0).ok_or_else(|| invalid("a whole number of seconds greater than zero"))?),
+                None => None,
+            },
+            "display" => self.display = value.map(String::from),
+            "timing" => self.timing = match value {
+                Some(v) => Some(v.parse::<Timing>().map(|_| v.to_string())?),
+                None => None,
+            },
+            "backend" => self.backend = match value {
+                Some(v) => Some(backend::check_spec(v).map(|_| v.to_string())?),
+                None => None,
+            },
+            "file" => self.file = value.map(String::from),
+            "verbose" => self.verbose = match value {
+                Some(v) => Some(v.parse().map_err(|_| invalid("true or false"))?),
+                None => None,
+            },
+            _ => return Err(unknown_setting(key)),
+        }
+        Ok(())
+    }
+}
+
+fn unknown_setting(key: &str) -> Error {
+    let keys: Vec<&str> = SETTINGS.iter().map(|(k, _)| *k).collect();
+    Error::InvalidInput(format!("Unknown setting {}; expected one of {}.", key, keys.join(", ")))
+}
+
+pub fn print_setting(key: &str, verbose: bool) -> Result<(), Error> {
+    let settings = fileio::load_settings(verbose)?;
+    let value = settings.get(key)?;
+    if output::json() {
+        return output::emit(&json!({ "key": key, "value": settings.json_value(key) }));
+    }
+    if let Some(v) = value {
+        out!("{}", v);
+    }
+    Ok(())
+}
+
+pub fn set_setting(key: &str, value: Option<&str>, verbose: bool) -> Result<(), Error> {
+    // settings always live in the default modes file, since `file` can point away from it
+    let mut set = serde_json::Value::Null;
+    fileio::update_config(None, verbose, |config| {
+        config.settings.set(key, value)?;
+        set = config.settings.json_value(key);
+        Ok(())
+    })?;
+    if output::json() {
+        return output::emit(&json!({ "key": key, "value": set }));
+    }
+    Ok(())
+}
+
+pub fn list_settings(verbose: bool) -> Result<(), Error> {
+    let settings = fileio::load_settings(verbose)?;
+    if output::json() {
+        let values: serde_json::Map<String, serde_json::Value> = SETTINGS.iter()
+            .map(|(key, _)| (key.to_string(), settings.json_value(key)))
+            .collect();
+        return output::emit(&json!({ "settings": values }));
+    }
+    for (key, about) in SETTINGS.iter() {
+        match settings.get(key)? {
+            Some(v) => out!("{:<8} = {}", key, v),
+            None if verbose => out!("{:<8}   (unset: {})", key, about),
+            None => out!("{:<8}   (unset)", key),
+        }
+    }
+    Ok(())
+}
+
+
+// Upgrades a parsed document one version at a time until it is current, returning it along with
+// the version it started at if that was an older one.
+fn migrate(mut value: Value, legacy_profiles: Option<&str>) -> Result<(Value, Option<u64>), serde_yaml::Error> {
+    let from = match &value {
+        // version 0: a bare list of modes, with profiles in a separate file
+        Value::Sequence(_) => 0,
+        // an empty file only counts as an old one if there are profiles to carry over
+        Value::Null if legacy_profiles.is_some() => 0,
+        Value::Null => return Ok((serde_yaml::to_value(Config::default())?, None)),
+        Value::Mapping(m) => match m.get(&Value::from("version")) {
+            Some(v) => v.as_u64().ok_or_else(|| serde_yaml::Error::custom("version must be a whole number"))?,
+            None => return Err(serde_yaml::Error::custom("missing version")),
+        },
+        _ => return Err(serde_yaml::Error::custom("expected a list of modes or a mapping with a version")),
+    };
+    if from > VERSION {
+        return Err(serde_yaml::Error::custom(format!("schema version {} was written by a newer cathode, which understands up to version {}", from, VERSION)));
+    }
+    let mut version = from;
+    while version < VERSION {
+        value = match version {
+            0 => v0_to_v1(value, legacy_profiles)?,
+            1 => v1_to_v2(value),
+            _ => unreachable!(),
+        };
+        version += 1;
+    }
+    Ok((value, if from < VERSION { Some(from) } else { None }))
+}
+
+// Wraps the list of modes in a versioned document and folds in profiles.yml
+fn v0_to_v1(modes: Value, legacy_profiles: Option<&str>) -> Result<Value, serde_yaml::Error> {
+    let modes = match modes {
+        Value::Null => Value::Sequence(Vec::new()),
+        m => m,
+    };
+    let profiles: Value = match legacy_profiles {
+        Some(s) if !s.trim().is_empty() => serde_yaml::from_str(s)?,
+        _ => Value::Null,
+    };
+    let section = |key: &str| profiles.get(key).cloned().unwrap_or_else(|| Value::Sequence(Vec::new()));
+    let mut doc = Mapping::new();
+    doc.insert(Value::from("version"), Value::from(1));
+    doc.insert(Value::from("modes"), modes);
+    doc.insert(Value::from("profiles"), section("profiles"));
+    doc.insert(Value::from("persisted"), section("persisted"));
+    doc.insert(Value::from("settings"), Value::Mapping(Mapping::new()));
+    Ok(Value::Mapping(doc))
+}
+
+// Splits each mode's flags, which version 1 kept as one space separated string, into a list
+fn v1_to_v2(mut doc: Value) -> Value {
+    if let Some(Value::Sequence(modes)) = doc.get_mut("modes") {
+        for mode in modes.iter_mut() {
+            if let Some(flags) = mode.get_mut("flags") {
+                if let Some(s) = flags.as_str() {
+                    *flags = Value::Sequence(s.split_whitespace().map(Value::from).collect());
+                }
+            }
+        }
+    }
+    if let Value::Mapping(m) = &mut doc {
+        m.insert(Value::from("version"), Value::from(2));
+    }
+    doc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODE: &str = "
+  name: 1920x1080_75.00
+  clock: \"220.75\"
+  h_disp: \"1920\"
+  h_sync_start: \"2064\"
+  h_sync_end: \"2264\"
+  h_total: \"2608\"
+  v_disp: \"1080\"
+  v_sync_start: \"1083\"
+  v_sync_end: \"1088\"
+  v_total: \"1130\"
+  flags: \"-hsync +vsync\"";
+
+    const PROFILES: &str = "
+profiles:
+  - name: docked
+    fingerprint: [\"DP-1:unknown\"]
+    outputs:
+      - display: DP-1
+        mode: 1920x1080_75.00
+persisted:
+  - display: HDMI-1
+    mode: 1920x1080_75.00";
+
+    #[test]
+    fn empty_file() {
+        let c = Config::from_yaml("", None).unwrap();
+        assert_eq!(c.version, VERSION);
+        assert!(c.modes.is_empty() && c.profiles.is_empty() && c.persisted.is_empty());
+        assert_eq!(c.migrated_from, None);
+    }
+
+    #[test]
+    fn v0_bare_list() {
+        let c = Config::from_yaml(&format!("---\n-{}", MODE), None).unwrap();
+        assert_eq!(c.migrated_from, Some(0));
+        assert_eq!(c.modes.len(), 1);
+        assert_eq!(c.modes[0].get_name(), "1920x1080_75.00");
+        assert!(c.profiles.is_empty() && c.persisted.is_empty());
+    }
+
+    #[test]
+    fn v0_with_profiles_file() {
+        let c = Config::from_yaml(&format!("---\n-{}", MODE), Some(PROFILES)).unwrap();
+        assert_eq!(c.migrated_from, Some(0));
+        assert_eq!(c.profiles.len(), 1);
+        assert_eq!(c.profiles[0].name, "docked");
+        assert_eq!(c.persisted, vec![Assignment::new("HDMI-1", "1920x1080_75.00", None, None)]);
+        // profiles saved before any mode was added
+        let c = Config::from_yaml("", Some(PROFILES)).unwrap();
+        assert_eq!(c.migrated_from, Some(0));
+        assert!(c.modes.is_empty());
+        assert_eq!(c.profiles.len(), 1);
+    }
+
+    #[test]
+    fn v1_document() {
+        let doc = format!("---\nversion: 1\nmodes:\n  -{}\nsettings: {{}}\n", MODE.replace("\n", "\n  "));
+        // profiles.yml is only read when migrating from version 0
+        let c = Config::from_yaml(&doc, Some(PROFILES)).unwrap();
+        assert_eq!(c.migrated_from, Some(1));
+        assert_eq!(c.modes.len(), 1);
+        assert_eq!(c.modes[0].flags, vec!["-hsync", "+vsync"]);
+        assert!(c.profiles.is_empty());
+    }
+
+    #[test]
+    fn v2_flags() {
+        let doc = "version: 2\nmodes:\n  - name: 1920x1080i\n    clock: \"74.25\"\n    h_disp: \"1920\"\n    h_sync_start: \"2008\"\n    h_sync_end: \"2052\"\n    h_total: \"2200\"\n    v_disp: \"1080\"\n    v_sync_start: \"1084\"\n    v_sync_end: \"1094\"\n    v_total: \"1125\"\n    flags: [interlace, +hsync, +vsync]\n";
+        let c = Config::from_yaml(doc, None).unwrap();
+        assert_eq!(c.migrated_from, None);
+        assert_eq!(c.modes[0].flags, vec!["interlace", "+hsync", "+vsync"]);
+        // field rate, as X and the kernel give it
+        assert!((c.modes[0].refresh_rate() - 60.0).abs() < 0.01);
+        let again = Config::from_yaml(&c.to_yaml(), None).unwrap();
+        assert_eq!(again.modes[0].flags, c.modes[0].flags);
+    }
+
+    #[test]
+    fn round_trip() {
+        let c = Config::from_yaml(&format!("---\n-{}", MODE), Some(PROFILES)).unwrap();
+        let again = Config::from_yaml(&c.to_yaml(), None).unwrap();
+        assert_eq!(again.migrated_from, None);
+        assert_eq!(again.modes.len(), 1);
+        assert_eq!(again.profiles.len(), 1);
+        assert_eq!(again.persisted, c.persisted);
+    }
+
+    #[test]
+    fn newer_version() {
+        let e = Config::from_yaml("version: 99\nmodes: []\n", None).unwrap_err();
+        assert!(e.to_string().contains("newer cathode"));
+    }
+
+    #[test]
+    fn v1_settings() {
+        let c = Config::from_yaml("version: 1\nsettings:\n  timeout: 5\n  backend: drm:/dev/dri/card1\n", None).unwrap();
+        assert_eq!(c.settings.get("timeout").unwrap().as_deref(), Some("5"));
+        assert_eq!(c.settings.get("backend").unwrap().as_deref(), Some("drm:/dev/dri/card1"));
+        assert_eq!(c.settings.get("display").unwrap(), None);
+        let mut s = c.settings.clone();
+        assert!(s.set("timeout", Some("0")).is_err());
+        assert!(s.set("timing", Some("gtf")).is_err());
+        assert!(s.set("colour", Some("blue")).is_err());
+        s.set("timeout", None).unwrap();
+        s.set("verbose", Some("true")).unwrap();
+        let again = Config::from_yaml(&Config { settings: s.clone(), ..c }.to_yaml(), None).unwrap();
+        assert_eq!(again.settings, s);
+    }
+
+    #[test]
+    fn missing_version() {
+        assert!(Config::from_yaml("modes: []\n", None).is_err());
+    }
+}
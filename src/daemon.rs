@@ -0,0 +1,37 @@
+use std::{thread,time};
+use std::result::Result;
+use crate::profile;
+use crate::backend::DisplayBackend;
+use crate::error::Error;
+
+/// How often the daemon polls when --interval isn't given, in seconds
+pub const DEFAULT_INTERVAL: u64 = 2;
+
+
+// Polls the backend for changes to the set of connected monitors and restores the matching
+// profile or persisted modes whenever it changes, including once at startup.
+pub fn run(backend: &mut dyn DisplayBackend, secs: u64, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    say!("Watching for display changes every {} secs.", secs);
+    let mut last: Option<Vec<String>> = None;
+    loop {
+        let outputs = match backend.outputs(false) {
+            Ok(o) => o,
+            // xrandr fails while X restarts and DRM devices can be busy during a hotplug, so try again next time
+            Err(e) => {
+                warn!("Error listing displays: {}", e);
+                thread::sleep(time::Duration::from_secs(secs));
+                continue;
+            }
+        };
+        let fp = profile::fingerprint(&outputs);
+        if last.as_ref() != Some(&fp) {
+            say!("Connected displays changed: {}", fp.join(", "));
+            if let Err(e) = profile::restore_outputs(backend, &outputs, f, verbose) {
+                // keep watching; the next hotplug may well succeed
+                warn!("Error restoring display configuration: {}", e);
+            }
+            last = Some(fp);
+        }
+        thread::sleep(time::Duration::from_secs(secs));
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize,Deserialize};
+use crate::error::Error;
+use crate::mode::Timings;
+
+
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+// Identifies a physical monitor independently of the connector it is plugged into.
+// Written as MFR-PRODUCT-SERIAL, e.g. GSM-5B09-203NTCZ4K118
+#[derive(Clone,Debug,PartialEq,Eq,Serialize,Deserialize)]
+pub struct MonitorId {
+    manufacturer: String,
+    product: u16,
+    serial: String,
+}
+
+impl fmt::Display for MonitorId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{:04X}-{}", self.manufacturer, self.product, self.serial)
+    }
+}
+
+impl FromStr for MonitorId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<MonitorId, String> {
+        let parts: Vec<&str> = s.splitn(3, '-').collect();
+        if parts.len() != 3 || parts[0].len() != 3 || !parts[0].chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(format!("{} is not a monitor identity of the form MFR-PRODUCT-SERIAL", s));
+        }
+        let product = u16::from_str_radix(parts[1], 16).map_err(|_| format!("{} is not a hexadecimal product code", parts[1]))?;
+        Ok(MonitorId {
+            manufacturer: parts[0].to_string(),
+            product,
+            serial: parts[2].to_string(),
+        })
+    }
+}
+
+// Derives the monitor identity from the vendor/product block of a base EDID, preferring the
+// serial number string descriptor over the numeric serial, which many vendors leave blank.
+pub fn parse_identity(edid: &[u8]) -> Option<MonitorId> {
+    if edid.len() < 128 || edid[0..8] != HEADER {
+        return None;
+    }
+    let id = u16::from_be_bytes([edid[8], edid[9]]);
+    let manufacturer: String = [10, 5, 0].iter()
+        .map(|shift| (b'A' - 1 + ((id >> shift) & 0x1f) as u8) as char)
+        .collect();
+    let product = u16::from_le_bytes([edid[10], edid[11]]);
+    let numeric_serial = u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]);
+    let serial = descriptor_string(edid, 0xff).unwrap_or_else(|| numeric_serial.to_string());
+    Some(MonitorId { manufacturer, product, serial })
+}
+
+// Returns the text of the first display descriptor with the given tag (0xff serial, 0xfc name)
+fn descriptor_string(edid: &[u8], tag: u8) -> Option<String> {
+    for offset in [54, 72, 90, 108].iter() {
+        let d = &edid[*offset..*offset + 18];
+        if d[0..3] == [0, 0, 0] && d[3] == tag {
+            let text: String = d[5..].iter()
+                .take_while(|b| **b != 0x0a)
+                .map(|b| *b as char)
+                .collect();
+            let text = text.trim().to_string();
+            if !text.is_empty() {
+                return Some(text);
+            }
+        }
+    }
+    None
+}
+
+// Encodes the timings as an 18-byte detailed timing descriptor. The image size is copied from
+// `size`, the bytes 12-14 of the descriptor being replaced, since the timings don't include it.
+pub fn detailed_timing(t: &Timings, size: [u8; 3]) -> Result<[u8; 18], Error> {
+    t.check_order()?;
+    let interlaced = t.has_flag("interlace");
+    // interlaced descriptors describe a single field
+    let field = |v: u32| if interlaced { v / 2 } else { v };
+    let clock = (t.clock * 100.0).round() as u32;
+    let h_blank = t.h_total - t.h_disp;
+    let h_front = t.h_sync_start - t.h_disp;
+    let h_sync = t.h_sync_end - t.h_sync_start;
+    let v_active = field(t.v_disp);
+    let v_blank = field(t.v_total) - v_active;
+    let v_front = field(t.v_sync_start) - v_active;
+    let v_sync = field(t.v_sync_end) - field(t.v_sync_start);
+    let limits = [
+        ("pixel clock", clock, 0xffff),
+        ("horizontal active", t.h_disp, 0xfff),
+        ("horizontal blanking", h_blank, 0xfff),
+        ("horizontal front porch", h_front, 0x3ff),
+        ("horizontal sync width", h_sync, 0x3ff),
+        ("vertical active", v_active, 0xfff),
+        ("vertical blanking", v_blank, 0xfff),
+        ("vertical front porch", v_front, 0x3f),
+        ("vertical sync width", v_sync, 0x3f),
+    ];
+    for (what, value, max) in limits.iter() {
+        if value > max {
+            return Err(Error::InvalidTiming(format!("The {} of {} does not fit in an EDID detailed timing descriptor.", what, value)));
+        }
+    }
+    if clock == 0 {
+        return Err(Error::InvalidTiming("An EDID detailed timing descriptor needs a non-zero pixel clock.".to_string()));
+    }
+    let mut d = [0u8; 18];
+    d[0..2].copy_from_slice(&(clock as u16).to_le_bytes());
+    d[2] = t.h_disp as u8;
+    d[3] = h_blank as u8;
+    d[4] = (((t.h_disp >> 8) << 4) | (h_blank >> 8)) as u8;
+    d[5] = v_active as u8;
+    d[6] = v_blank as u8;
+    d[7] = (((v_active >> 8) << 4) | (v_blank >> 8)) as u8;
+    d[8] = h_front as u8;
+    d[9] = h_sync as u8;
+    d[10] = (((v_front & 0xf) << 4) | (v_sync & 0xf)) as u8;
+    d[11] = (((h_front >> 8) << 6) | ((h_sync >> 8) << 4) | ((v_front >> 4) << 2) | (v_sync >> 4)) as u8;
+    d[12..15].copy_from_slice(&size);
+    // digital separate sync, with the polarities from the modeline
+    let mut flags = 0x18;
+    if interlaced {
+        flags |= 0x80;
+    }
+    if t.has_flag("+vsync") {
+        flags |= 0x04;
+    }
+    if t.has_flag("+hsync") {
+        flags |= 0x02;
+    }
+    d[17] = flags;
+    Ok(d)
+}
+
+// Returns a copy of the EDID with `timing` as its first (preferred) detailed timing descriptor.
+// The timing it displaces moves to the first descriptor slot that is unused or a dummy, so the
+// monitor name, serial number and range limits are kept. Extension blocks are left untouched.
+pub fn insert_preferred_timing(edid: &[u8], timing: &[u8; 18]) -> Result<Vec<u8>, Error> {
+    if edid.len() < 128 || edid[0..8] != HEADER {
+        return Err(Error::InvalidData("Not a valid EDID: the base block header is missing.".to_string()));
+    }
+    let mut out = edid.to_vec();
+    let previous: [u8; 18] = {
+        let mut p = [0u8; 18];
+        p.copy_from_slice(&out[54..72]);
+        p
+    };
+    out[54..72].copy_from_slice(timing);
+    if is_detailed_timing(&previous) && previous != *timing {
+        let free = [72, 90, 108].iter().copied().find(|o| {
+            let d = &out[*o..*o + 18];
+            // dummy descriptor (0x10) or a slot that was never filled in
+            d[0..3] == [0, 0, 0] && (d[3] == 0x10 || d.iter().all(|b| *b == 0))
+        });
+        if let Some(o) = free {
+            out[o..o + 18].copy_from_slice(&previous);
+        }
+    }
+    // EDID 1.3 only treats the first descriptor as preferred if the feature bit says so
+    out[24] |= 0x02;
+    out[127] = checksum(&out[0..127]);
+    Ok(out)
+}
+
+// The image size (bytes 12-14) of the current preferred timing, or the screen size in
+// centimetres from the base block if there isn't one
+pub fn image_size(edid: &[u8]) -> [u8; 3] {
+    if edid.len() >= 128 && is_detailed_timing(&edid[54..72]) {
+        return [edid[66], edid[67], edid[68]];
+    }
+    let (w, h) = if edid.len() >= 128 { (u32::from(edid[21]) * 10, u32::from(edid[22]) * 10) } else { (0, 0) };
+    [w as u8, h as u8, (((w >> 8) << 4) | (h >> 8)) as u8]
+}
+
+fn is_detailed_timing(d: &[u8]) -> bool {
+    d[0] != 0 || d[1] != 0
+}
+
+fn checksum(block: &[u8]) -> u8 {
+    let sum = block.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    0u8.wrapping_sub(sum)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(clock: f64, h: [u32; 4], v: [u32; 4], flags: &[&str]) -> Timings {
+        Timings {
+            clock,
+            h_disp: h[0], h_sync_start: h[1], h_sync_end: h[2], h_total: h[3],
+            v_disp: v[0], v_sync_start: v[1], v_sync_end: v[2], v_total: v[3],
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    // a base block with the 1080p timing as its only descriptor
+    fn base_block() -> Vec<u8> {
+        let mut edid = vec![0u8; 128];
+        edid[0..8].copy_from_slice(&HEADER);
+        edid[54..72].copy_from_slice(&[0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0x50, 0x1d, 0x31, 0x00, 0x00, 0x1e]);
+        edid[72..77].copy_from_slice(&[0, 0, 0, 0xfc, 0]);
+        edid[77..90].copy_from_slice(b"Test\n        ");
+        edid[90..95].copy_from_slice(&[0, 0, 0, 0x10, 0]);
+        edid[127] = checksum(&edid[0..127]);
+        edid
+    }
+
+    #[test]
+    fn timings_encode_as_in_real_edids() {
+        // CEA-861 1920x1080 at 60 Hz, as found in most monitors' EDIDs
+        let t = timings(148.5, [1920, 2008, 2052, 2200], [1080, 1084, 1089, 1125], &["+hsync", "+vsync"]);
+        assert_eq!(detailed_timing(&t, [0x50, 0x1d, 0x31]).unwrap(),
+            [0x02, 0x3a, 0x80, 0x18, 0x71, 0x38, 0x2d, 0x40, 0x58, 0x2c, 0x45, 0x00, 0x50, 0x1d, 0x31, 0x00, 0x00, 0x1e]);
+        // CVT reduced blanking 1920x1080 at 60 Hz
+        let t = timings(138.5, [1920, 1968, 2000, 2080], [1080, 1083, 1088, 1111], &["+hsync", "-vsync"]);
+        assert_eq!(detailed_timing(&t, [0x50, 0x1d, 0x31]).unwrap(),
+            [0x1a, 0x36, 0x80, 0xa0, 0x70, 0x38, 0x1f, 0x40, 0x30, 0x20, 0x35, 0x00, 0x50, 0x1d, 0x31, 0x00, 0x00, 0x1a]);
+    }
+
+    #[test]
+    fn timings_too_big_for_a_descriptor_are_rejected() {
+        let t = timings(700.0, [1920, 2008, 2052, 2200], [1080, 1084, 1089, 1125], &[]);
+        assert!(detailed_timing(&t, [0; 3]).is_err());
+    }
+
+    #[test]
+    fn inserted_timing_is_preferred_and_checksummed() {
+        let edid = base_block();
+        let t = timings(138.5, [1920, 1968, 2000, 2080], [1080, 1083, 1088, 1111], &["+hsync", "-vsync"]);
+        let timing = detailed_timing(&t, image_size(&edid)).unwrap();
+        let out = insert_preferred_timing(&edid, &timing).unwrap();
+        assert_eq!(out[54..72], timing);
+        // the old preferred timing took the dummy descriptor's place, and the name is kept
+        assert_eq!(out[90..108], edid[54..72]);
+        assert_eq!(out[72..90], edid[72..90]);
+        assert_eq!(out[24] & 0x02, 0x02);
+        assert_eq!(out.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)), 0);
+    }
+
+    #[test]
+    fn insertion_needs_an_edid() {
+        assert!(insert_preferred_timing(&[0u8; 128], &[1u8; 18]).is_err());
+        assert!(insert_preferred_timing(&HEADER, &[1u8; 18]).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{error,fmt,io};
+
+
+/// Everything that can make a cathode command fail. Each kind exits with its own status so
+/// scripts can tell a missing mode from a display that rejected it.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file, or some other system call, failed
+    Io(io::Error),
+    /// An argument or setting had an invalid value
+    InvalidInput(String),
+    /// No saved or X server mode has the requested name
+    ModeNotFound(String),
+    /// The display is not connected or does not exist
+    DisplayNotFound(String),
+    /// The modes file could not be parsed
+    ConfigParse(String),
+    /// The timings of a mode are impossible or out of range
+    InvalidTiming(String),
+    /// The display server, driver or an external command refused the request
+    BackendFailure { command: String, stderr: String, reason: String },
+    /// A mode with that name already exists
+    ModeExists(String),
+    /// A program cathode runs, such as cvt or xrandr, is not installed
+    MissingTool(String),
+    /// The backend or hardware cannot do what was asked
+    Unsupported(String),
+    /// A file cathode was asked to read does not hold what it should
+    InvalidData(String),
+}
+
+impl Error {
+    /// A failure that did not come from running a command
+    pub fn backend<S: Into<String>>(reason: S) -> Error {
+        Error::BackendFailure { command: String::new(), stderr: String::new(), reason: reason.into() }
+    }
+
+    /// The process exit status for this kind of failure; 1 is left for I/O errors and clap uses it for usage errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::InvalidInput(_) => 2,
+            Error::ModeNotFound(_) => 3,
+            Error::DisplayNotFound(_) => 4,
+            Error::ConfigParse(_) => 5,
+            Error::InvalidTiming(_) => 6,
+            Error::BackendFailure { .. } => 7,
+            Error::ModeExists(_) => 8,
+            Error::MissingTool(_) => 9,
+            Error::Unsupported(_) => 10,
+            Error::InvalidData(_) => 11,
+        }
+    }
+
+    /// The stable name used for this kind of failure in JSON output
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => "not_found",
+                io::ErrorKind::PermissionDenied => "permission_denied",
+                io::ErrorKind::AlreadyExists => "already_exists",
+                _ => "io",
+            },
+            Error::InvalidInput(_) => "invalid_input",
+            Error::ModeNotFound(_) => "mode_not_found",
+            Error::DisplayNotFound(_) => "display_not_found",
+            Error::ConfigParse(_) => "config_parse",
+            Error::InvalidTiming(_) => "invalid_timing",
+            Error::BackendFailure { .. } => "backend_failure",
+            Error::ModeExists(_) => "mode_exists",
+            Error::MissingTool(_) => "missing_tool",
+            Error::Unsupported(_) => "unsupported",
+            Error::InvalidData(_) => "invalid_data",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::BackendFailure { command, stderr, reason } => {
+                if command.is_empty() {
+                    write!(f, "{}", reason)
+                } else {
+                    write!(f, "{}. `{}` said: {}", reason, command, if stderr.is_empty() { "nothing" } else { stderr })
+                }
+            },
+            Error::MissingTool(tool) => write!(f, "{} is not installed or not on $PATH.", tool),
+            Error::InvalidInput(m) | Error::ModeNotFound(m) | Error::DisplayNotFound(m) | Error::ConfigParse(m)
+                | Error::InvalidTiming(m) | Error::ModeExists(m) | Error::Unsupported(m) | Error::InvalidData(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
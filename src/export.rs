@@ -0,0 +1,198 @@
+use std::{fs,path};
+use std::result::Result;
+use crate::{command,config,edid,fileio,mode,output,util};
+use crate::backend::{self,DisplayBackend};
+use crate::error::Error;
+use crate::mode::Timing;
+
+
+// Prints the video= kernel parameter which sets a saved mode from boot, before any display server starts
+pub fn export_kernel(backend: &mut dyn DisplayBackend, n: &str, d: Option<&str>, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let mode = fileio::get_mode(n, f, verbose)?;
+    let display = kernel_connector(backend, &mode, d, verbose)?;
+    let parameter = kernel_parameter(&mode, &display)?;
+    check_kernel_timings(&mode, verbose)?;
+    if output::json() {
+        return output::emit(&serde_json::json!({ "display": display, "parameter": parameter }));
+    }
+    out!("{}", parameter);
+    Ok(())
+}
+
+// Writes the display's EDID with a saved mode inserted as the preferred timing, to be loaded with drm.edid_firmware
+pub fn export_edid(backend: &mut dyn DisplayBackend, n: &str, d: Option<&str>, input: Option<&str>, output: Option<&str>, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let mode = fileio::get_mode(n, f, verbose)?;
+    let display = kernel_connector(backend, &mode, d, verbose)?;
+    let original = match input {
+        Some(i) => {
+            if verbose {
+                say!("Reading EDID from {}", i);
+            }
+            fs::read(i)?
+        }
+        None => backend.outputs(verbose)?.into_iter()
+            .find(|o| o.display == display)
+            .and_then(|o| o.edid)
+            .ok_or_else(|| Error::DisplayNotFound(format!("Could not read the EDID of display {}; pass one with --input.", display)))?,
+    };
+    let timing = edid::detailed_timing(&mode.timings()?, edid::image_size(&original))?;
+    let patched = edid::insert_preferred_timing(&original, &timing)?;
+    let out = path::PathBuf::from(output.map(String::from).unwrap_or_else(|| format!("{}-{}.bin", display, n)));
+    if command::dry_run() {
+        say!("Would write {}", out.display());
+        return Ok(());
+    }
+    if verbose {
+        say!("Writing to {}", out.display());
+    }
+    fs::write(&out, patched)?;
+    if output::json() {
+        output::emit(&serde_json::json!({ "display": display, "file": out }))?;
+    }
+    let file = out.file_name().and_then(|f| f.to_str()).unwrap_or("");
+    say!("Wrote {}. Copy it to /usr/lib/firmware/edid/ and add drm.edid_firmware={}:edid/{} to the kernel command line.", out.display(), display, file);
+    Ok(())
+}
+
+// Prints saved modes in a format other tools understand; all of them unless one is named
+pub fn export_modes(n: Option<&str>, format: Format, d: Option<&str>, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let modes = match n {
+        Some(name) => vec![fileio::get_mode(name, f, verbose)?],
+        None => fileio::import_all_modes(f, verbose)?,
+    };
+    let out = match format {
+        Format::Xrandr => xrandr_script(&modes, d)?,
+        Format::Modeline => modeline_block(&modes)?,
+        Format::Json => serde_json::to_string_pretty(&modes).map_err(|e| Error::InvalidData(e.to_string()))? + "\n",
+        // a modes file of its own, which cathode can use with --filename
+        Format::Yaml => config::Config { modes: modes.clone(), ..Default::default() }.to_yaml(),
+    };
+    if output::json() {
+        return output::emit(&match format {
+            Format::Xrandr => serde_json::json!({ "script": out }),
+            Format::Modeline => serde_json::json!({ "modelines": out }),
+            Format::Json | Format::Yaml => serde_json::json!({ "modes": modes }),
+        });
+    }
+    out!("{}", out.trim_end_matches('\n'));
+    Ok(())
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Format {
+    Xrandr,
+    Modeline,
+    Json,
+    Yaml,
+}
+
+// A shell script which creates the modes with xrandr, and adds them to the display if one is given
+pub fn xrandr_script(modes: &[mode::CvtMode], d: Option<&str>) -> Result<String, Error> {
+    let mut s = String::from("#!/bin/sh\n# Generated by cathode\n");
+    for m in modes {
+        s += &format!("xrandr --newmode {}\n", timings_args(m, util::shell_quote(m.get_name()))?);
+        if let Some(display) = d {
+            s += &format!("xrandr --addmode {} {}\n", util::shell_quote(display), util::shell_quote(m.get_name()));
+        }
+    }
+    if let (Some(display), [m]) = (d, modes) {
+        s += &format!("xrandr --output {} --mode {}\n", util::shell_quote(display), util::shell_quote(m.get_name()));
+    }
+    Ok(s)
+}
+
+// Modeline entries for the Monitor section of an xorg.conf, laid out the way cvt prints them
+pub fn modeline_block(modes: &[mode::CvtMode]) -> Result<String, Error> {
+    let mut s = String::new();
+    for m in modes {
+        s += &format!("# {}x{} {:.2} Hz\n", m.width(), m.height(), m.refresh_rate());
+        s += &format!("Modeline {}\n", timings_args(m, format!("\"{}\"", m.get_name()))?);
+    }
+    Ok(s)
+}
+
+fn timings_args(m: &mode::CvtMode, name: String) -> Result<String, Error> {
+    let t = m.timings()?;
+    let mut s = format!("{}  {}  {} {} {} {}  {} {} {} {}", name, m.clock, t.h_disp, t.h_sync_start, t.h_sync_end, t.h_total, t.v_disp, t.v_sync_start, t.v_sync_end, t.v_total);
+    for flag in &t.flags {
+        s.push(' ');
+        s.push_str(flag);
+    }
+    Ok(s)
+}
+
+// The kernel's own CVT calculation is asked for (M), with reduced blanking (R) when the mode
+// uses the reduced blanking sync polarities, so that timings the monitor's EDID doesn't list
+// come out the same as cvt's.
+pub fn kernel_parameter(mode: &mode::CvtMode, display: &str) -> Result<String, Error> {
+    let t = mode.timings()?;
+    let reduced = t.has_flag("+hsync") && t.has_flag("-vsync");
+    Ok(format!("video={}:{}x{}M{}@{}{}",
+        display,
+        t.h_disp,
+        t.v_disp,
+        if reduced { "R" } else { "" },
+        mode.refresh_rate().round(),
+        if t.has_flag("interlace") { "i" } else { "" }))
+}
+
+// The M suffix has the kernel compute the timings itself, so a mode with timings of its own
+// would come out differently at boot. cvt stands in for the kernel's calculation.
+fn check_kernel_timings(mode: &mode::CvtMode, verbose: bool) -> Result<(), Error> {
+    let t = mode.timings()?;
+    let timing = if t.has_flag("+hsync") && t.has_flag("-vsync") { Timing::CvtReducedBlanking } else { Timing::Cvt };
+    match mode::generate(mode.get_name(), t.h_disp, t.v_disp, mode.refresh_rate().round(), timing, t.has_flag("interlace"), verbose) {
+        Ok(generated) => {
+            if !generated.timings()?.same_as(&t) {
+                warn!("Warning: mode {} does not have the CVT timings the kernel will compute for it at boot; use export edid to keep its exact timings.", mode.get_name());
+            }
+        }
+        Err(e) => {
+            if verbose {
+                say!("Could not check the timings the kernel will compute: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The display doesn't have to be connected to generate a parameter for it, but it does have to be
+// named the way the kernel names it (DP-1, HDMI-A-1), which isn't always the name X uses.
+fn kernel_connector(backend: &mut dyn DisplayBackend, mode: &mode::CvtMode, d: Option<&str>, verbose: bool) -> Result<String, Error> {
+    let outputs = backend.outputs(verbose).unwrap_or_default();
+    let display = match d {
+        Some(spec) => backend::find_output(spec, &outputs).map(|o| o.display.clone()).unwrap_or_else(|| spec.to_string()),
+        None => mode::target_output(mode, None, &outputs)?.display.clone(),
+    };
+    let known = fs::read_dir("/sys/class/drm").map(|entries| entries.filter_map(|e| e.ok()).any(|e| {
+        e.file_name().to_str().map(|n| n.ends_with(&format!("-{}", display))).unwrap_or(false)
+    })).unwrap_or(true);
+    if !known {
+        warn!("Warning: the kernel has no connector named {}; check /sys/class/drm for the right name.", display);
+    }
+    Ok(display)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import;
+
+    // whatever cathode exports, it has to be able to import again
+    #[test]
+    fn round_trip() {
+        let modes = import::parse_modelines("Modeline \"1920x1080_75.00\" 220.75 1920 2064 2264 2608 1080 1083 1088 1130 -hsync +vsync\nModeline \"my mode\" 25.175 640 656 752 800 480 490 492 525 -hsync -vsync interlace");
+        assert_eq!(modes.len(), 2);
+        let script = xrandr_script(&modes, Some("DP-1")).unwrap();
+        assert!(script.contains("xrandr --addmode DP-1 'my mode'\n"));
+        for out in [script, modeline_block(&modes).unwrap()].iter() {
+            let again = import::parse_modelines(out);
+            assert_eq!(again.len(), 2);
+            for (a, b) in modes.iter().zip(again.iter()) {
+                assert_eq!(a.get_name(), b.get_name());
+                assert!(a.timings().unwrap().same_as(&b.timings().unwrap()));
+            }
+        }
+    }
+}
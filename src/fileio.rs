@@ -1,69 +1,284 @@
-use std::fs;
-use std::io::{Error,ErrorKind};
+use std::{fs,path,process,time};
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::result::Result;
 
-use serde_yaml;
 
-use crate::{mode,util};
+use crate::{command,config,edid,mode,output,profile,util};
+use crate::error::Error;
 
 
-pub fn import_all_modes(filename: Option<&str>, verbose: bool) -> Result<Vec<mode::CvtMode>, Error> {
+/// Reads the modes file, upgrading it in memory if it was written with an older schema.
+/// The upgraded document is written out the next time anything is saved.
+pub fn load_config(filename: Option<&str>, verbose: bool) -> Result<config::Config, Error> {
     let f = util::filename_or_default(filename,verbose)?;
-    let file = fs::OpenOptions::new().write(true).read(true).create(true).open(f)?;
-    let mut buf_reader = BufReader::new(file);
     let mut contents = String::new();
-    buf_reader.read_to_string(&mut contents)?;
-    let modes = match serde_yaml::from_str(&contents) {
-        Ok(m) => m,
-        Err(_) => Vec::<mode::CvtMode>::new()
-
-    };
+    if command::dry_run() {
+        // a modes file that doesn't exist yet reads as an empty one, rather than being created
+        match fs::File::open(&f) {
+            Ok(file) => { BufReader::new(file).read_to_string(&mut contents)?; }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        let file = fs::OpenOptions::new().write(true).read(true).create(true).truncate(false).open(&f)?;
+        BufReader::new(file).read_to_string(&mut contents)?;
+    }
+    let p = legacy_profiles_filename(&f);
+    let legacy = if p.is_file() { Some(fs::read_to_string(&p)?) } else { None };
+    let config = config::Config::from_yaml(&contents, legacy.as_deref()).map_err(|e| parse_error(&f, &e))?;
     if verbose {
-        for mode in &modes {
-            println!("Found mode {:?}", mode);
+        if let Some(v) = config.migrated_from {
+            say!("{} uses schema version {}; upgrading to version {}.", f.display(), v, config::VERSION);
+        }
+        for mode in &config.modes {
+            say!("Found mode {:?}", mode);
+        }
+    }
+    Ok(config)
+}
+
+/// Reads, modifies and rewrites the modes file while holding its lock
+pub fn update_config<F: FnOnce(&mut config::Config) -> Result<(), Error>>(filename: Option<&str>, verbose: bool, update: F) -> Result<(), Error> {
+    let f = util::filename_or_default(filename,verbose)?;
+    let dry_run = command::dry_run();
+    // held until the new file is in place, so concurrent saves can't lose each other's changes
+    let _lock = if dry_run { None } else { Some(lock(&f, verbose)?) };
+    // refuses to go any further if the existing file can't be parsed, rather than replacing it
+    let mut config = load_config(filename, verbose)?;
+    update(&mut config)?;
+    let contents = config.to_yaml();
+    if dry_run {
+        print_diff(&f, &fs::read_to_string(&f).unwrap_or_default(), &contents);
+    } else {
+        backup(&f, verbose)?;
+        write_atomic(&f, contents.as_bytes(), verbose)?;
+        prune_backups(&f, verbose)?;
+    }
+    if config.migrated_from == Some(0) {
+        // its contents now live in the modes file; keep it around under another name
+        let p = legacy_profiles_filename(&f);
+        if p.is_file() {
+            let mut name = p.file_name().unwrap().to_os_string();
+            name.push(".migrated");
+            let m = p.with_file_name(name);
+            if dry_run {
+                say!("Would move {} to {}", p.display(), m.display());
+            } else {
+                if verbose {
+                    say!("Moving {} to {}", p.display(), m.display());
+                }
+                fs::rename(&p, &m)?;
+            }
         }
     }
-    Ok(modes)
+    Ok(())
+}
+
+// Settings are always read from the default modes file, whichever file --filename names
+pub fn load_settings(verbose: bool) -> Result<config::Settings, Error> {
+    // settings only ever come from the default file, and looking them up must not create it
+    let f = util::default_filename(verbose)?;
+    if !f.is_file() {
+        return Ok(config::Settings::default());
+    }
+    let contents = fs::read_to_string(&f)?;
+    Ok(config::Config::from_yaml(&contents, None).map_err(|e| parse_error(&f, &e))?.settings)
+}
+
+pub fn import_all_modes(filename: Option<&str>, verbose: bool) -> Result<Vec<mode::CvtMode>, Error> {
+    Ok(load_config(filename, verbose)?.modes)
 }
 
 pub fn save_mode(mode: &mode::CvtMode, filename: Option<&str>, verbose: bool) -> Result<(), Error> {
+    save_modes(std::slice::from_ref(mode), filename, verbose)
+}
+
+// Saves several modes in a single rewrite of the file, replacing any with the same names
+pub fn save_modes(modes: &[mode::CvtMode], filename: Option<&str>, verbose: bool) -> Result<(), Error> {
+    update_config(filename, verbose, |config| {
+        for mode in modes {
+            if verbose && config.get_mode(mode.get_name()).is_some() {
+                say!("Mode {} already exists. Overwriting.", mode.get_name());
+            }
+            config.add_mode(mode.clone());
+        }
+        Ok(())
+    })
+}
+
+pub fn get_mode(name: &str, filename: Option<&str>, verbose: bool) -> Result<mode::CvtMode, Error> {
+    let config = load_config(filename, verbose)?;
+    config.get_mode(name).cloned().ok_or_else(|| Error::ModeNotFound(format!("No mode named {} is saved.", name)))
+}
+
+// Records the mode as the one to use for the display whenever `restore` or the daemon runs
+pub fn save_mode_persistent(mode: &mode::CvtMode, display: &str, monitor: Option<edid::MonitorId>, filename: Option<&str>, verbose: bool) -> Result<(), Error> {
+    update_config(filename, verbose, |config| {
+        config.add_persisted(profile::Assignment::new(display, mode.get_name(), Some(mode.refresh_rate()), monitor));
+        Ok(())
+    })
+}
+
+// Validates the modes file, reporting every problem found rather than stopping at the first
+pub fn check_modes(filename: Option<&str>, verbose: bool) -> Result<(), Error> {
     let f = util::filename_or_default(filename,verbose)?;
-    let mut v = import_all_modes(filename,verbose)?;
-    let n = mode.get_name();
-    for i in 0 .. v.len() {
-        if v[i].get_name() == n {
-            if verbose {
-                println!("Mode {} already exists in file {}. Overwriting.",n,f.to_str().unwrap());
+    let config = load_config(filename, verbose)?;
+    let modes = &config.modes;
+    if let Some(v) = config.migrated_from {
+        say!("{}: uses schema version {}; it will be upgraded to version {} the next time it is written.", f.display(), v, config::VERSION);
+    }
+    let mut problems: Vec<String> = Vec::new();
+    for (i, m) in modes.iter().enumerate() {
+        if modes[..i].iter().any(|o| o.get_name() == m.get_name()) {
+            problems.push(format!("mode {} is defined more than once", m.get_name()));
+        }
+        match m.timings() {
+            Ok(t) => {
+                if let Err(e) = t.check_order() {
+                    problems.push(format!("mode {}: {}", m.get_name(), e));
+                }
             }
-            v.remove(i);
+            Err(e) => problems.push(e.to_string()),
         }
     }
-    v.push(mode.clone());
-    let s = serde_yaml::to_string(&v).unwrap();
+    if output::json() {
+        output::emit(&serde_json::json!({
+            "file": f,
+            "version": config.migrated_from.unwrap_or(config::VERSION),
+            "modes": modes.len(),
+            "problems": problems,
+        }))?;
+    }
+    if problems.is_empty() {
+        say!("{}: {} mode(s), no problems found.", f.display(), modes.len());
+        return Ok(());
+    }
+    for p in &problems {
+        warn!("{}: {}", f.display(), p);
+    }
+    Err(Error::InvalidData(format!("Found {} problem(s) in {}.", problems.len(), f.display())))
+}
+
+// Takes an exclusive advisory lock on <file>.lock, blocking until any other cathode process
+// writing the file is done. The lock is released when the returned file is dropped.
+fn lock(f: &path::Path, verbose: bool) -> Result<fs::File, Error> {
+    let mut name = f.file_name().unwrap().to_os_string();
+    name.push(".lock");
+    let l = f.with_file_name(name);
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&l)?;
     if verbose {
-        println!("Writing to {}",f.to_str().unwrap());
+        say!("Locking {}", l.display());
     }
-    // truncating here deletes the existing content of the file and replaces it with the mode saved/
-    let mut file = fs::OpenOptions::new().write(true).truncate(true).open(f)?;
-    file.write_all(s.as_bytes())?;
-    Ok(())
+    file.lock()?;
+    Ok(file)
 }
 
-pub fn get_mode(name: &str, filename: Option<&str>, verbose: bool) -> Result<mode::CvtMode, Error> {
-    let modes = import_all_modes(filename, verbose)?;
-    for m in modes {
-        if m.get_name() == name {
-            return Ok(m)
+// Writes to a temporary file in the same directory and renames it over the original, so a
+// crash part way through leaves either the old contents or the new ones, never a mix.
+// A symlinked modes file stays a link: it is the file it points to that gets replaced, and
+// the replacement keeps that file's permissions.
+fn write_atomic(f: &path::Path, contents: &[u8], verbose: bool) -> Result<(), Error> {
+    let target = fs::canonicalize(f).unwrap_or_else(|_| f.to_path_buf());
+    let mut name = std::ffi::OsString::from(".");
+    name.push(target.file_name().unwrap_or(f.as_os_str()));
+    name.push(format!(".{}.tmp", process::id()));
+    let tmp = target.with_file_name(name);
+    if verbose {
+        say!("Writing to {}",target.display());
+    }
+    let permissions = fs::metadata(&target).map(|m| m.permissions()).ok();
+    let written = fs::File::create(&tmp).and_then(|mut file| {
+        file.write_all(contents)?;
+        if let Some(p) = permissions {
+            file.set_permissions(p)?;
+        }
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp, &target)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    // make the rename itself durable
+    if let Some(dir) = target.parent() {
+        if let Ok(d) = fs::File::open(dir) {
+            let _ = d.sync_all();
         }
     }
-    Err(Error::new(ErrorKind::NotFound, "Mode not found."))
+    Ok(())
+}
+
+// Version 0 kept profiles and persisted modes in profiles.yml next to the modes file
+fn legacy_profiles_filename(f: &path::Path) -> path::PathBuf {
+    f.with_file_name("profiles.yml")
 }
 
-pub fn save_mode_persistent(_mode: &mode::CvtMode, _verbose: bool) -> Result<(), Error> {
-    if cfg!(target_os = "linux") {
-        let _file = fs::OpenOptions::new().append(true).open("$HOME/.xprofile")?;
+// Shows what a write would change, for --dry-run
+fn print_diff(f: &path::Path, old: &str, new: &str) {
+    let name = &*f.to_string_lossy();
+    if old == new {
+        say!("Would leave {} unchanged.", name);
+        return;
+    }
+    say!("Would write {}:", name);
+    let diff = similar::TextDiff::from_lines(old, new);
+    say!("{}", diff.unified_diff().context_radius(3).header(name, name).to_string().trim_end());
+}
+
+fn parse_error(f: &path::Path, e: &serde_yaml::Error) -> Error {
+    let msg = e.to_string();
+    let (at, msg) = match e.location() {
+        // serde_yaml appends the location to its message; report it up front instead
+        Some(l) => (format!(":{}:{}", l.line(), l.column()), msg.trim_end_matches(&format!(" at line {} column {}", l.line(), l.column())).to_string()),
+        None => (String::new(), msg),
+    };
+    Error::ConfigParse(format!("Could not parse {}{}: {}. Fix or move the file; cathode will not write to it until then.", f.display(), at, msg))
+}
+
+// Copies the file to <file>.<unix time>.bak before it is rewritten. Only the first backup made
+// in any given second is kept, since that is the one holding the state before the rewrites.
+fn backup(f: &path::Path, verbose: bool) -> Result<(), Error> {
+    if fs::metadata(f).map(|m| m.len() == 0).unwrap_or(true) {
+        return Ok(());
+    }
+    let secs = time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut name = f.file_name().unwrap().to_os_string();
+    name.push(format!(".{}.bak", secs));
+    let b = f.with_file_name(name);
+    if b.exists() {
+        return Ok(());
+    }
+    if verbose {
+        say!("Backing up {} to {}", f.display(), b.display());
+    }
+    fs::copy(f, &b)?;
+    Ok(())
+}
+
+// Backups kept of the modes file; older ones are deleted once a rewrite has succeeded
+const BACKUPS: usize = 5;
+
+fn prune_backups(f: &path::Path, verbose: bool) -> Result<(), Error> {
+    let (dir, file) = match (f.parent(), f.file_name().and_then(|n| n.to_str())) {
+        (Some(d), Some(n)) => (if d.as_os_str().is_empty() { path::Path::new(".") } else { d }, n),
+        _ => return Ok(()),
+    };
+    let prefix = format!("{}.", file);
+    let mut backups: Vec<(u64, path::PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let secs = name.strip_prefix(&prefix)?.strip_suffix(".bak")?.parse().ok()?;
+            Some((secs, e.path()))
+        })
+        .collect();
+    backups.sort();
+    let old = backups.len().saturating_sub(BACKUPS);
+    for (_, b) in &backups[..old] {
+        if verbose {
+            say!("Removing old backup {}", b.display());
+        }
+        fs::remove_file(b)?;
     }
     Ok(())
 }
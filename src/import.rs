@@ -0,0 +1,144 @@
+use std::fs;
+use std::result::Result;
+use regex::Regex;
+use crate::{fileio,output};
+use crate::error::Error;
+use crate::mode::CvtMode;
+
+
+// Flags xrandr and the X server accept after the timings of a modeline
+const FLAGS: [&str; 10] = ["+hsync", "-hsync", "+vsync", "-vsync", "+csync", "-csync", "csync", "composite", "interlace", "doublescan"];
+
+
+// Finds every `Modeline` (xorg.conf, cvt and gtf output) and `xrandr --newmode` entry in the
+// text, leaving out those commented out with a # earlier on their line
+pub fn parse_modelines(text: &str) -> Vec<CvtMode> {
+    let re = Regex::new(r#"(?i)(?:\bmodeline|--newmode)\s+("[^"\n]*"|'[^'\n]*'|[^\s"']+)\s+(\d+(?:\.\d+)?)\s+(\d+)\s+(\d+)\s+(\d+)\s+(\d+)\s+(\d+)\s+(\d+)\s+(\d+)\s+(\d+)([^\n]*)"#).unwrap();
+    re.captures_iter(text).filter(|c| {
+        let start = c.get(0).map(|m| m.start()).unwrap_or(0);
+        let line = text[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        !text[line..start].contains('#')
+    }).map(|c| {
+        let flags: Vec<String> = c[11].split_whitespace()
+            .map(|f| f.to_ascii_lowercase())
+            .take_while(|f| FLAGS.contains(&f.as_str()))
+            .collect();
+        let mut mode = CvtMode {
+            name: c[1].trim_matches(|c| c == '"' || c == '\'').to_string(),
+            clock: c[2].to_string(),
+            h_disp: c[3].to_string(),
+            h_sync_start: c[4].to_string(),
+            h_sync_end: c[5].to_string(),
+            h_total: c[6].to_string(),
+            v_disp: c[7].to_string(),
+            v_sync_start: c[8].to_string(),
+            v_sync_end: c[9].to_string(),
+            v_total: c[10].to_string(),
+            flags,
+            monitor: None,
+        };
+        if mode.name.is_empty() {
+            mode.name = format!("{}x{}_{:.2}", mode.h_disp, mode.v_disp, mode.refresh_rate());
+        }
+        mode
+    }).collect()
+}
+
+// Adds the modes found in a file to the modes file, skipping any whose timings are already saved
+pub fn import_modes(path: &str, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let found = parse_modelines(&fs::read_to_string(path)?);
+    if found.is_empty() {
+        return Err(Error::InvalidData(format!("No Modeline or xrandr --newmode entries found in {}.", path)));
+    }
+    if verbose {
+        say!("Found {} mode(s) in {}.", found.len(), path);
+    }
+    let existing = fileio::import_all_modes(f, verbose)?;
+    let mut new: Vec<CvtMode> = Vec::new();
+    let mut skipped: Vec<String> = Vec::new();
+    for m in found {
+        let t = m.timings()?;
+        if let Err(e) = t.check_order() {
+            warn!("Warning: skipping mode {}: {}.", m.get_name(), e);
+            skipped.push(m.name);
+            continue;
+        }
+        let saved = existing.iter().chain(new.iter());
+        if let Some(same) = saved.clone().find(|s| s.timings().map(|st| st.same_as(&t)).unwrap_or(false)) {
+            say!("Skipping mode {}; its timings are already saved as {}.", m.get_name(), same.get_name());
+            skipped.push(m.name);
+            continue;
+        }
+        if saved.clone().any(|s| s.get_name() == m.get_name()) {
+            warn!("Warning: skipping mode {}; a mode with that name but different timings is already saved.", m.get_name());
+            skipped.push(m.name);
+            continue;
+        }
+        say!("Importing mode {} ({}x{} at {:.2} Hz).", m.get_name(), m.width(), m.height(), m.refresh_rate());
+        new.push(m);
+    }
+    if new.is_empty() {
+        say!("Nothing new to import.");
+    } else {
+        fileio::save_modes(&new, f, verbose)?;
+        say!("Imported {} mode(s).", new.len());
+    }
+    if output::json() {
+        let imported: Vec<&str> = new.iter().map(|m| m.get_name()).collect();
+        output::emit(&serde_json::json!({ "imported": imported, "skipped": skipped }))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xorg_conf() {
+        let text = r#"
+Section "Monitor"
+    Identifier "DP-1"
+    # 1920x1080 74.91 Hz (CVT 2.07M9) hsync: 84.64 kHz; pclk: 220.75 MHz
+    Modeline "1920x1080_75.00"  220.75  1920 2064 2264 2608  1080 1083 1088 1130 -HSync +Vsync
+    ModeLine "small" 25.175 640 656 752 800 480 490 492 525 -hsync -vsync Interlace
+    Option "PreferredMode" "1920x1080_75.00"
+EndSection
+"#;
+        let modes = parse_modelines(text);
+        assert_eq!(modes.len(), 2);
+        assert_eq!(modes[0].get_name(), "1920x1080_75.00");
+        assert_eq!(modes[0].clock, "220.75");
+        assert_eq!(modes[0].v_total, "1130");
+        assert_eq!(modes[0].flags, vec!["-hsync", "+vsync"]);
+        assert_eq!(modes[1].flags, vec!["-hsync", "-vsync", "interlace"]);
+    }
+
+    #[test]
+    fn xrandr_script() {
+        let text = "#!/bin/sh\nxrandr --newmode 2560x1440_144 586.59 2560 2608 2640 2720 1440 1443 1448 1497 +hsync -vsync && xrandr --addmode DP-1 2560x1440_144\n";
+        let modes = parse_modelines(text);
+        assert_eq!(modes.len(), 1);
+        assert_eq!(modes[0].get_name(), "2560x1440_144");
+        assert_eq!(modes[0].flags, vec!["+hsync", "-vsync"]);
+        assert!((modes[0].refresh_rate() - 144.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn commented_out_entries_are_skipped() {
+        let text = "  # Modeline \"old\" 25.175 640 656 752 800 480 490 492 525 -hsync -vsync\n#xrandr --newmode old2 25.175 640 656 752 800 480 490 492 525\nxrandr --newmode new 25.175 640 656 752 800 480 490 492 525 # was old\n";
+        let modes = parse_modelines(text);
+        assert_eq!(modes.len(), 1);
+        assert_eq!(modes[0].get_name(), "new");
+    }
+
+    #[test]
+    fn dedupe_by_timings() {
+        let a = &parse_modelines("Modeline \"a\" 220.75 1920 2064 2264 2608 1080 1083 1088 1130 -hsync +vsync")[0];
+        let b = &parse_modelines("xrandr --newmode \"b\" 220.750 1920 2064 2264 2608 1080 1083 1088 1130 +VSync -HSync")[0];
+        let c = &parse_modelines("Modeline \"c\" 220.75 1920 2064 2264 2608 1080 1083 1088 1131 -hsync +vsync")[0];
+        assert!(a.timings().unwrap().same_as(&b.timings().unwrap()));
+        assert!(!a.timings().unwrap().same_as(&c.timings().unwrap()));
+    }
+}
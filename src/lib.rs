@@ -0,0 +1,51 @@
+//! Create, test and apply custom display modes through xrandr, a wlroots compositor or DRM.
+//!
+//! The `cathode` binary is a thin front-end over this crate. Library functions never print:
+//! progress, warnings and command output go to the [`output::Reporter`] installed with
+//! [`output::set_reporter`], and are dropped if there is none.
+//!
+//! ```no_run
+//! use cathode::{backend, mode, Timing};
+//!
+//! # fn main() -> Result<(), cathode::Error> {
+//! let mut backend = backend::detect(None, false)?;
+//! let outputs = backend.outputs(false)?;
+//! let display = &outputs[0];
+//! let new = mode::generate("2560x1440_75", 2560, 1440, 75.0, Timing::CvtReducedBlanking, false, false)?;
+//! mode::register_mode(backend.as_mut(), &new, &display.display, false)?;
+//! if let Some(preferred) = &display.preferred {
+//!     mode::test(backend.as_mut(), &new, &[(&display.display, preferred)], 10, false)?;
+//! }
+//! mode::apply(backend.as_mut(), &new, &display.display, false)?;
+//! # Ok(())
+//! # }
+//! ```
+
+extern crate regex;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate yaml_rust;
+
+#[macro_use]
+pub mod output;
+pub mod backend;
+pub mod command;
+pub mod config;
+pub mod daemon;
+pub mod edid;
+pub mod error;
+pub mod export;
+pub mod fileio;
+pub mod import;
+pub mod mode;
+pub mod pattern;
+pub mod profile;
+pub mod status;
+pub mod verify;
+mod util;
+
+pub use crate::backend::DisplayBackend;
+pub use crate::config::{Config,Settings};
+pub use crate::error::Error;
+pub use crate::mode::{apply,generate,test,CvtMode,Timing,Timings};
@@ -1,31 +1,97 @@
 #[macro_use]
 extern crate clap;
-extern crate regex;
-extern crate serde;
-extern crate serde_yaml;
-extern crate yaml_rust;
-use std::io::Error;
+extern crate cathode;
+use std::io;
+use std::process;
+use std::str::FromStr;
 use std::result::Result;
+use clap::ArgMatches;
+use cathode::{backend,command,config,daemon,export,fileio,import,mode,output,pattern,profile,status,verify};
+use cathode::error::Error;
+use cathode::output::Channel;
 
-mod fileio;
-mod mode;
-mod util;
+mod tune;
 
-fn main() -> Result<(), Error> {
+
+// Prints what the library reports: results on stdout, warnings on stderr, and progress on
+// stdout unless it is reserved for JSON
+struct Terminal;
+
+impl output::Reporter for Terminal {
+    fn report(&self, channel: Channel, message: &str) {
+        if tune::capture(message) {
+            return;
+        }
+        match channel {
+            Channel::Result => println!("{}", message),
+            Channel::Info if !output::json() => println!("{}", message),
+            _ => eprintln!("{}", message),
+        }
+    }
+
+    fn confirm(&self, question: &str) -> bool {
+        self.report(Channel::Info, question);
+        loop {
+            let mut input = String::new();
+            match io::stdin().read_line(&mut input) {
+                // no one left to answer, so don't keep an untested mode
+                Ok(0) | Err(_) => return false,
+                _ if input.contains('y') => return true,
+                _ if input.contains('n') => return false,
+                _ => (),
+            }
+        }
+    }
+}
+
+fn main() {
+    output::set_reporter(Box::new(Terminal));
+    if let Err(e) = run() {
+        print_error(&e);
+        process::exit(e.exit_code());
+    }
+}
+
+// Reports an error the way the chosen output format expects
+fn print_error(e: &Error) {
+    if output::json() {
+        let mut error = serde_json::json!({
+            "code": e.code(),
+            "exit_code": e.exit_code(),
+            "message": e.to_string(),
+        });
+        if let Error::BackendFailure { command, stderr, .. } = e {
+            if !command.is_empty() {
+                error["command"] = command.as_str().into();
+                error["stderr"] = stderr.as_str().into();
+            }
+        }
+        let _ = output::emit(&serde_json::json!({ "error": error }));
+    } else {
+        eprintln!("Error: {}", e);
+    }
+}
+
+fn run() -> Result<(), Error> {
     let matches = clap_app!(cathode =>
                             (version: "0.1.0")
                             (author: "Thor McAvenia <mcaveniathor@gmail.com>")
                             (@arg verbose: -v --verbose "Enable verbose output for all subcommands.")
-                            (@arg import: -i --import "Load modes from $HOME/.config/cathode/modes.yml or the file specified by the FILENAME parameter")
+                            (@arg load: -i --import "Load modes from $HOME/.config/cathode/modes.yml or the file specified by the FILENAME parameter")
                             (@arg filename: -f --filename [filename] "Specify a modes file to load")
+                            (@arg dryrun: --("dry-run") "Print the xrandr commands and modes file changes cathode would make instead of making them.")
+                            (@arg output: --output [format] "Output format: human or json. json prints one JSON object per line on stdout and everything else on stderr.")
+                            (@arg backend: -b --backend [backend] "Backend to use: xrandr, wlr, drm or drm:<device>. Defaults to wlr under Wayland, xrandr under X and drm otherwise.")
                             (@subcommand add =>
                                 (about: "create a new mode.")
-                                (@arg width: -w --width [width] "width in pixels. defaults to the currently active value")
-                                (@arg height: -h --height [height] "display height in pixels defaults to the currently active value.")
-                                (@arg rate: -r --rate [rate] "refresh rate in hz. defaults to the currently active value.")
-                                (@arg display: -d --display [display] "display for which to add the mode. defaults to the first connected display.")
+                                (@arg width: -w --width [width] "width in pixels. defaults to the value of the display's current mode")
+                                (@arg height: -h --height [height] "display height in pixels defaults to the value of the display's current mode.")
+                                (@arg rate: -r --rate [rate] "refresh rate in hz. defaults to the value of the display's current mode.")
+                                (@arg display: -d --display [display] "display or monitor identity (MFR-PRODUCT-SERIAL) for which to add the mode. defaults to the first connected display.")
                                 (@arg name: -n --name [name] "the name of the mode. defaults to <width>x<height>_<rate>")
                                 (@arg timeout: -t --timeout [timeout] "Specify a timeout duration in seconds. Implies --test.")
+                                (@arg timing: --timing [timing] "Timing formula: cvt, or cvt-rb for reduced blanking. defaults to cvt or the timing setting.")
+                                (@arg interlaced: --interlaced "Generate an interlaced mode, as cvt -i does. The refresh rate is then the field rate.")
                                 (@arg test: --test "Apply this mode temporarily to see if it works (useful for monitor overclocking). Reverts to the default mode after 10 seconds or TIMEOUT if --timeout is used.")
                                 (@arg nosave: --nosave "Do not write this mode to file.")
                                 (@arg verbose: -v --verbose "Enable verbose output for add subcommand.")
@@ -33,40 +99,259 @@ fn main() -> Result<(), Error> {
                             (@subcommand apply =>
                              (about: "Apply a display mode to a display.")
                              (@arg name: -n --name <name> "Name of the mode to be applied.")
-                             (@arg display: -d --display <display> "Display to which the mode should be applied.")
+                             (@arg display: -d --display [display]... number_of_values(1) "Display or monitor identity (MFR-PRODUCT-SERIAL) to which the mode should be applied, or all for every connected display. Repeat to apply to several displays at once. Defaults to the monitor the mode was created for.")
                              (@arg test: --test "Apply this mode temporarily to see if it works (useful for monitor overclocking). Reverts to the default mode after 10 seconds or TIMEOUT if --timeout is used.")
                              (@arg timeout: -t --timeout [timeout] "Specify a timeout duration. Implies --test.")
-                             (@arg persist: -p --persist "Automatically apply this mode when you log in to this user. This places xrandr commands in $HOME/.xprofile.")
+                             (@arg persist: -p --persist "Automatically apply this mode to this display whenever `cathode restore` or `cathode daemon` runs.")
                              (@arg verbose: -v --verbose "Enable verbose output for apply subcommand.")
                             )
+                            (@subcommand export =>
+                             (about: "Export a saved mode for use outside of cathode.")
+                             (@arg verbose: -v --verbose "Enable verbose output for export subcommand.")
+                             (@subcommand kernel =>
+                              (about: "Print the video= kernel parameter which sets a saved mode at boot.")
+                              (@arg name: -n --name <name> "Name of the mode to export.")
+                              (@arg display: -d --display [display] "Kernel connector name (e.g. DP-1, HDMI-A-1) or monitor identity. Defaults to the monitor the mode was created for.")
+                             )
+                             (@subcommand edid =>
+                              (about: "Write the display's EDID with a saved mode inserted as the preferred timing, for use with drm.edid_firmware.")
+                              (@arg name: -n --name <name> "Name of the mode to export.")
+                              (@arg display: -d --display [display] "Kernel connector name (e.g. DP-1, HDMI-A-1) or monitor identity. Defaults to the monitor the mode was created for.")
+                              (@arg input: -i --input [input] "Read the EDID to modify from this file instead of from the display.")
+                              (@arg output: -o --output [output] "File to write the EDID to. Defaults to <display>-<name>.bin")
+                             )
+                             (@subcommand xrandr =>
+                              (about: "Print a shell script which creates saved modes with xrandr.")
+                              (@arg name: -n --name [name] "Name of the mode to export. Defaults to all saved modes.")
+                              (@arg display: -d --display [display] "Also add the modes to this display, and switch to the mode if there is only one.")
+                             )
+                             (@subcommand modeline =>
+                              (about: "Print saved modes as Modeline entries for xorg.conf.")
+                              (@arg name: -n --name [name] "Name of the mode to export. Defaults to all saved modes.")
+                             )
+                             (@subcommand json =>
+                              (about: "Print saved modes as JSON.")
+                              (@arg name: -n --name [name] "Name of the mode to export. Defaults to all saved modes.")
+                             )
+                             (@subcommand yaml =>
+                              (about: "Print saved modes as a modes file of their own, which can be used with --filename.")
+                              (@arg name: -n --name [name] "Name of the mode to export. Defaults to all saved modes.")
+                             )
+                            )
+                            (@subcommand import =>
+                             (about: "Import the Modeline and xrandr --newmode entries in a file, such as an xorg.conf or .xprofile.")
+                             (@arg file: +required "File to import modes from.")
+                             (@arg verbose: -v --verbose "Enable verbose output for import subcommand.")
+                            )
+                            (@subcommand list =>
+                             (about: "List connected displays and the modes they support.")
+                             (@arg verbose: -v --verbose "Enable verbose output for list subcommand.")
+                            )
+                            (@subcommand profile =>
+                             (about: "Save the current display configuration as a profile for the connected monitors.")
+                             (@arg name: -n --name <name> "Name of the profile.")
+                             (@arg verbose: -v --verbose "Enable verbose output for profile subcommand.")
+                            )
+                            (@subcommand restore =>
+                             (about: "Apply the profile matching the connected monitors, or the persisted modes if no profile matches.")
+                             (@arg verbose: -v --verbose "Enable verbose output for restore subcommand.")
+                            )
+                            (@subcommand status =>
+                             (about: "Show what each output is running: its mode, timings, refresh rate, position and rotation.")
+                             (@arg verbose: -v --verbose "Enable verbose output for status subcommand.")
+                            )
+                            (@subcommand check =>
+                             (about: "Check the modes file for errors.")
+                             (@arg verbose: -v --verbose "Enable verbose output for check subcommand.")
+                            )
+                            (@subcommand config =>
+                             (about: "Show or change the settings used as defaults for command line options.")
+                             (@arg verbose: -v --verbose "Enable verbose output for config subcommand.")
+                             (@subcommand get =>
+                              (about: "Print the value of a setting.")
+                              (@arg key: +required "Name of the setting.")
+                             )
+                             (@subcommand set =>
+                              (about: "Change the value of a setting.")
+                              (@arg key: +required "Name of the setting.")
+                              (@arg value: +required "New value of the setting.")
+                             )
+                             (@subcommand unset =>
+                              (about: "Reset a setting to its default.")
+                              (@arg key: +required "Name of the setting.")
+                             )
+                             (@subcommand list =>
+                              (about: "List all settings and their values.")
+                             )
+                            )
+                            (@subcommand daemon =>
+                             (about: "Watch for displays being connected or disconnected and restore the matching profile or persisted modes.")
+                             (@arg interval: -i --interval [interval] "Polling interval in seconds. Defaults to 2.")
+                             (@arg verbose: -v --verbose "Enable verbose output for daemon subcommand.")
+                            )
+                            (@subcommand pattern =>
+                             (about: "Show a fullscreen test pattern for spotting skipped frames, optionally while testing a saved mode. Needs an X server.")
+                             (@arg name: -n --name [name] "A saved mode to test while the pattern is shown. Defaults to the mode the display is running.")
+                             (@arg display: -d --display [display] "The display to show the pattern on.")
+                             (@arg timeout: -t --timeout [timeout] "How long to show the pattern for, in seconds.")
+                             (@arg verbose: -v --verbose "Enable verbose output for pattern subcommand.")
+                            )
+                            (@subcommand verify =>
+                             (about: "Measure the refresh rate a display is really running at from its vblank timestamps, and report jitter and skipped frames.")
+                             (@arg display: -d --display [display] "The display to measure. Defaults to the first display running a mode.")
+                             (@arg seconds: -s --seconds [seconds] "How long to measure for. Defaults to 5.")
+                             (@arg verbose: -v --verbose "Enable verbose output for verify subcommand.")
+                            )
+                            (@subcommand tune =>
+                             (about: "Interactively adjust a mode's timings, test the result and save it.")
+                             (@arg timeout: -t --timeout [timeout] "How long a tested mode stays before it is reverted, in seconds.")
+                             (@arg verbose: -v --verbose "Enable verbose output for tune subcommand.")
+                            )
                            ).get_matches();
     // TODO: automatic OC
-    let v = matches.is_present("verbose");
-    let filename = matches.value_of("filename");
-    if matches.is_present("import") {
-        fileio::import_all_modes(filename,v)?;
+    command::set_dry_run(matches.is_present("dryrun"));
+    match matches.value_of("output") {
+        None | Some("human") => (),
+        Some("json") => output::set_json(true),
+        Some(o) => return Err(Error::InvalidInput(format!("Unknown output format {}; expected human or json.", o))),
+    }
+    // a broken modes file shouldn't stop `check` from reporting on it, so fall back to the defaults
+    let settings = fileio::load_settings(matches.is_present("verbose")).unwrap_or_else(|e| {
+        eprintln!("Warning: could not read settings: {}", e);
+        config::Settings::default()
+    });
+    let v = matches.is_present("verbose") || settings.verbose.unwrap_or(false);
+    let filename = matches.value_of("filename").or(settings.file.as_deref());
+    if let Some(configmatches) = matches.subcommand_matches("config") {
+        let verbose = v || configmatches.is_present("verbose");
+        if let Some(getmatches) = configmatches.subcommand_matches("get") {
+            return config::print_setting(getmatches.value_of("key").unwrap(), verbose)
+        }
+        if let Some(setmatches) = configmatches.subcommand_matches("set") {
+            return config::set_setting(setmatches.value_of("key").unwrap(), setmatches.value_of("value"), verbose)
+        }
+        if let Some(unsetmatches) = configmatches.subcommand_matches("unset") {
+            return config::set_setting(unsetmatches.value_of("key").unwrap(), None, verbose)
+        }
+        return config::list_settings(verbose)
+    }
+    let mut backend = backend::detect(matches.value_of("backend").or(settings.backend.as_deref()), v)?;
+    if matches.is_present("load") {
+        let modes = fileio::import_all_modes(filename,v)?;
+        if output::json() {
+            output::emit(&serde_json::json!({ "modes": modes }))?;
+        }
     }
     if let Some(addmatches) = matches.subcommand_matches("add") {
         let verbose = v || addmatches.is_present("verbose");
-        let width = addmatches.value_of("width");
-        let height = addmatches.value_of("height");
-        let rate = addmatches.value_of("rate");
-        let name = addmatches.value_of("name");
-        let display = addmatches.value_of("display");
-        let test = addmatches.is_present("test") || addmatches.is_present("timeout");
-        let timeout = addmatches.value_of("timeout");
-        let save = !addmatches.is_present("nosave");
-        return mode::add_mode(width, height, rate, display, name, timeout, filename, test, save, verbose)
+        let request = mode::AddModeRequest {
+            width: positive(addmatches, "width", "width")?,
+            height: positive(addmatches, "height", "height")?,
+            rate: positive(addmatches, "rate", "refresh rate")?,
+            display: addmatches.value_of("display").or(settings.display.as_deref()).map(String::from),
+            name: addmatches.value_of("name").map(String::from),
+            timing: addmatches.value_of("timing").or(settings.timing.as_deref()).unwrap_or("cvt").parse()?,
+            interlaced: addmatches.is_present("interlaced"),
+            test: addmatches.is_present("test") || addmatches.is_present("timeout"),
+            timeout: positive(addmatches, "timeout", "timeout")?.or(settings.timeout).unwrap_or(mode::DEFAULT_TIMEOUT),
+            save: !addmatches.is_present("nosave"),
+            filename: filename.map(String::from),
+        };
+        return mode::add_mode(backend.as_mut(), &request, verbose)
     }
     if let Some(applymatches) = matches.subcommand_matches("apply") {
         let verbose = v || applymatches.is_present("verbose");
-        let name = applymatches.value_of("name").unwrap(); // required; unwrap rather than error check
-        let test = applymatches.is_present("test") || applymatches.is_present("timeout");
-        let timeout = applymatches.value_of("test");
-        let display = applymatches.value_of("display").unwrap(); // required; unwrap rather than error check
-        let persist = applymatches.is_present("persist");
-        return mode::apply_mode(name,display,timeout,test,persist,verbose)
+        let request = mode::ApplyRequest {
+            displays: applymatches.values_of("display").map(|d| d.map(String::from).collect()).unwrap_or_default(),
+            test: applymatches.is_present("test") || applymatches.is_present("timeout"),
+            timeout: positive(applymatches, "timeout", "timeout")?.or(settings.timeout).unwrap_or(mode::DEFAULT_TIMEOUT),
+            persist: applymatches.is_present("persist"),
+            filename: filename.map(String::from),
+            ..mode::ApplyRequest::new(applymatches.value_of("name").unwrap()) // required; unwrap rather than error check
+        };
+        return mode::apply_mode(backend.as_mut(), &request, verbose)
+    }
+    if let Some(exportmatches) = matches.subcommand_matches("export") {
+        let verbose = v || exportmatches.is_present("verbose");
+        if let Some(kernelmatches) = exportmatches.subcommand_matches("kernel") {
+            let name = kernelmatches.value_of("name").unwrap(); // required; unwrap rather than error check
+            let display = kernelmatches.value_of("display");
+            return export::export_kernel(backend.as_mut(), name, display, filename, verbose)
+        }
+        if let Some(edidmatches) = exportmatches.subcommand_matches("edid") {
+            let name = edidmatches.value_of("name").unwrap(); // required; unwrap rather than error check
+            let display = edidmatches.value_of("display");
+            let input = edidmatches.value_of("input");
+            let output = edidmatches.value_of("output");
+            return export::export_edid(backend.as_mut(), name, display, input, output, filename, verbose)
+        }
+        for (sub, format) in [("xrandr", export::Format::Xrandr), ("modeline", export::Format::Modeline), ("json", export::Format::Json), ("yaml", export::Format::Yaml)].iter() {
+            if let Some(formatmatches) = exportmatches.subcommand_matches(sub) {
+                return export::export_modes(formatmatches.value_of("name"), *format, formatmatches.value_of("display"), filename, verbose)
+            }
+        }
+        println!("{}", exportmatches.usage());
+        return Ok(())
+    }
+    if let Some(importmatches) = matches.subcommand_matches("import") {
+        let verbose = v || importmatches.is_present("verbose");
+        return import::import_modes(importmatches.value_of("file").unwrap(), filename, verbose)
+    }
+    if let Some(listmatches) = matches.subcommand_matches("list") {
+        let verbose = v || listmatches.is_present("verbose");
+        return mode::list_outputs(backend.as_mut(), verbose)
+    }
+    if let Some(profilematches) = matches.subcommand_matches("profile") {
+        let verbose = v || profilematches.is_present("verbose");
+        let name = profilematches.value_of("name").unwrap(); // required; unwrap rather than error check
+        return profile::save_profile(backend.as_mut(), name, filename, verbose)
+    }
+    if let Some(restorematches) = matches.subcommand_matches("restore") {
+        let verbose = v || restorematches.is_present("verbose");
+        profile::restore(backend.as_mut(), filename, verbose)?;
+        return Ok(())
+    }
+    if let Some(statusmatches) = matches.subcommand_matches("status") {
+        let verbose = v || statusmatches.is_present("verbose");
+        return status::print_status(backend.as_mut(), filename, verbose)
+    }
+    if let Some(checkmatches) = matches.subcommand_matches("check") {
+        let verbose = v || checkmatches.is_present("verbose");
+        return fileio::check_modes(filename, verbose)
+    }
+    if let Some(daemonmatches) = matches.subcommand_matches("daemon") {
+        let verbose = v || daemonmatches.is_present("verbose");
+        let interval = positive(daemonmatches, "interval", "interval")?.unwrap_or(daemon::DEFAULT_INTERVAL);
+        return daemon::run(backend.as_mut(), interval, filename, verbose)
+    }
+    if let Some(patternmatches) = matches.subcommand_matches("pattern") {
+        let verbose = v || patternmatches.is_present("verbose");
+        let display = patternmatches.value_of("display").or(settings.display.as_deref());
+        let timeout = positive(patternmatches, "timeout", "timeout")?.or(settings.timeout).unwrap_or(mode::DEFAULT_TIMEOUT);
+        return pattern::show_pattern(backend.as_mut(), patternmatches.value_of("name"), display, timeout, filename, verbose)
+    }
+    if let Some(verifymatches) = matches.subcommand_matches("verify") {
+        let verbose = v || verifymatches.is_present("verbose");
+        let display = verifymatches.value_of("display").or(settings.display.as_deref());
+        let seconds = positive(verifymatches, "seconds", "duration")?.unwrap_or(5);
+        return verify::verify(backend.as_mut(), display, seconds, verbose)
+    }
+    if let Some(tunematches) = matches.subcommand_matches("tune") {
+        let verbose = v || tunematches.is_present("verbose");
+        let timeout = positive(tunematches, "timeout", "timeout")?.or(settings.timeout).unwrap_or(mode::DEFAULT_TIMEOUT);
+        return tune::run(backend.as_mut(), filename, timeout, verbose)
     }
     Ok(())
 }
 
+
+// Parses a numeric option, which has to be greater than zero
+fn positive<T: FromStr + PartialOrd + Default>(matches: &ArgMatches, key: &str, what: &str) -> Result<Option<T>, Error> {
+    match matches.value_of(key) {
+        None => Ok(None),
+        Some(value) => match value.parse() {
+            Ok(n) if n > T::default() => Ok(Some(n)),
+            _ => Err(Error::InvalidInput(format!("Invalid {} {}; expected a number greater than zero.", what, value))),
+        }
+    }
+}
@@ -1,51 +1,181 @@
-use std::{io,process,str,thread,time};
-use std::io::Error;
+use std::{process,str,thread,time};
 use std::result::Result;
-use regex::Regex;
 use serde::{Serialize,Deserialize};
-use crate::{fileio,util};
-
-#[derive(Debug)]
-pub struct InputMode {
-    width:String,
-    height:String,
-    rate:String,
-    name:String,
-    display:String,
-}
+use serde_json::json;
+use crate::{command,edid,fileio,import,output,util};
+use crate::backend::{self,DisplayBackend,ModeRef,Output,OutputMode};
+use crate::error::Error;
+
+/// How long a mode is tested for when neither --timeout nor the timeout setting is given
+pub const DEFAULT_TIMEOUT: u64 = 10;
 
+/// The formulas cvt can generate timings with. Reduced blanking shortens the blanking intervals
+/// for LCDs, lowering the pixel clock needed for a given refresh rate.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Timing {
+    Cvt,
+    CvtReducedBlanking,
+}
 
-impl InputMode {
-    pub fn new(width:&str,height:&str,rate:&str,display:&str,name:&str) -> InputMode {
-        InputMode {
-            width:width.to_string(),
-            height:height.to_string(),
-            rate:rate.to_string(),
-            display:display.to_string(),
-            name:name.to_string()
+impl str::FromStr for Timing {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Timing, Error> {
+        match s {
+            "cvt" => Ok(Timing::Cvt),
+            "cvt-rb" => Ok(Timing::CvtReducedBlanking),
+            _ => Err(Error::InvalidInput(format!("Unknown timing {}; expected cvt or cvt-rb.", s))),
         }
     }
 }
 
+/// A custom mode as saved in the modes file. Create one with [`generate`] or
+/// [`crate::import::parse_modelines`].
 #[derive(Clone,Debug,Serialize,Deserialize)]
 pub struct CvtMode {
-    name: String,
-    clock: String,
-    h_disp: String,
-    h_sync_start: String,
-    h_sync_end: String,
-    h_total: String,
-    v_disp: String,
-    v_sync_start: String,
-    v_sync_end: String,
-    v_total: String,
-    flags: String,
+    pub(crate) name: String,
+    pub(crate) clock: String,
+    pub(crate) h_disp: String,
+    pub(crate) h_sync_start: String,
+    pub(crate) h_sync_end: String,
+    pub(crate) h_total: String,
+    pub(crate) v_disp: String,
+    pub(crate) v_sync_start: String,
+    pub(crate) v_sync_end: String,
+    pub(crate) v_total: String,
+    /// +hsync, -vsync, interlace, doublescan and so on, each as it appears in a modeline
+    pub(crate) flags: Vec<String>,
+    /// the monitor this mode was created for, if its EDID could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) monitor: Option<edid::MonitorId>,
+}
+
+/// The numeric timings of a CvtMode; clock is in MHz
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct Timings {
+    pub clock: f64,
+    pub h_disp: u32,
+    pub h_sync_start: u32,
+    pub h_sync_end: u32,
+    pub h_total: u32,
+    pub v_disp: u32,
+    pub v_sync_start: u32,
+    pub v_sync_end: u32,
+    pub v_total: u32,
+    pub flags: Vec<String>,
+}
+
+impl Timings {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+    /// Whether two modes would drive the display identically, whatever they are called. Clocks
+    /// are compared to the kHz, since modelines round them to different numbers of places.
+    pub fn same_as(&self, other: &Timings) -> bool {
+        let flags = |t: &Timings| {
+            let mut f: Vec<String> = t.flags.iter().map(|f| f.to_ascii_lowercase()).collect();
+            f.sort();
+            f
+        };
+        (self.clock - other.clock).abs() < 0.0005
+            && (self.h_disp, self.h_sync_start, self.h_sync_end, self.h_total) == (other.h_disp, other.h_sync_start, other.h_sync_end, other.h_total)
+            && (self.v_disp, self.v_sync_start, self.v_sync_end, self.v_total) == (other.v_disp, other.v_sync_start, other.v_sync_end, other.v_total)
+            && flags(self) == flags(other)
+    }
+    // Fields per frame: an interlaced mode scans each frame in two fields, and a doublescan
+    // mode scans every line twice, which is how X and the kernel count their refresh rates
+    fn scans(&self) -> f64 {
+        let mut scans = 1.0;
+        if self.has_flag("interlace") {
+            scans *= 2.0;
+        }
+        if self.has_flag("doublescan") {
+            scans /= 2.0;
+        }
+        scans
+    }
+    /// Vertical refresh rate in Hz; the field rate for interlaced modes
+    pub fn refresh_rate(&self) -> f64 {
+        if self.h_total == 0 || self.v_total == 0 {
+            return 0.0;
+        }
+        self.clock * 1_000_000.0 * self.scans() / (f64::from(self.h_total) * f64::from(self.v_total))
+    }
+    /// Horizontal scan rate in kHz
+    pub fn line_rate(&self) -> f64 {
+        if self.h_total == 0 {
+            return 0.0;
+        }
+        self.clock * 1000.0 / f64::from(self.h_total)
+    }
+    /// Changes the pixel clock so the mode refreshes at `rate` Hz with the same totals.
+    /// The clock is rounded to the kHz, as modelines give it.
+    pub fn set_refresh_rate(&mut self, rate: f64) {
+        let clock = rate * f64::from(self.h_total) * f64::from(self.v_total) / (1_000_000.0 * self.scans());
+        self.clock = (clock * 1000.0).round() / 1000.0;
+    }
+    /// The sync pulses have to start after the active area and end before the total
+    pub fn check_order(&self) -> Result<(), Error> {
+        if self.h_disp <= self.h_sync_start && self.h_sync_start <= self.h_sync_end && self.h_sync_end <= self.h_total
+            && self.v_disp <= self.v_sync_start && self.v_sync_start <= self.v_sync_end && self.v_sync_end <= self.v_total {
+            Ok(())
+        } else {
+            Err(Error::InvalidTiming(String::from("the sync pulses do not lie within the blanking intervals")))
+        }
+    }
 }
 
 impl CvtMode {
+    /// A mode with the given name and timings, not bound to any monitor
+    pub fn from_timings(name: &str, t: &Timings) -> CvtMode {
+        let clock = format!("{:.3}", t.clock);
+        CvtMode {
+            name: name.to_string(),
+            clock: clock.trim_end_matches('0').trim_end_matches('.').to_string(),
+            h_disp: t.h_disp.to_string(),
+            h_sync_start: t.h_sync_start.to_string(),
+            h_sync_end: t.h_sync_end.to_string(),
+            h_total: t.h_total.to_string(),
+            v_disp: t.v_disp.to_string(),
+            v_sync_start: t.v_sync_start.to_string(),
+            v_sync_end: t.v_sync_end.to_string(),
+            v_total: t.v_total.to_string(),
+            flags: t.flags.clone(),
+            monitor: None,
+        }
+    }
     pub fn get_name(&self) -> &str {
         &self.name
     }
+    /// Records the monitor the mode was made for, so `apply` can find it on any connector
+    pub fn set_monitor(&mut self, monitor: Option<edid::MonitorId>) {
+        self.monitor = monitor;
+    }
+    pub fn width(&self) -> u32 {
+        self.h_disp.parse().unwrap_or(0)
+    }
+    pub fn height(&self) -> u32 {
+        self.v_disp.parse().unwrap_or(0)
+    }
+    pub fn timings(&self) -> Result<Timings, Error> {
+        let invalid = |field: &str| Error::InvalidTiming(format!("Mode {} has an invalid {}.", self.name, field));
+        let timing = |field: &str, value: &str| -> Result<u32, Error> { value.parse().map_err(|_| invalid(field)) };
+        Ok(Timings {
+            clock: self.clock.parse().map_err(|_| invalid("clock"))?,
+            h_disp: timing("h_disp", &self.h_disp)?,
+            h_sync_start: timing("h_sync_start", &self.h_sync_start)?,
+            h_sync_end: timing("h_sync_end", &self.h_sync_end)?,
+            h_total: timing("h_total", &self.h_total)?,
+            v_disp: timing("v_disp", &self.v_disp)?,
+            v_sync_start: timing("v_sync_start", &self.v_sync_start)?,
+            v_sync_end: timing("v_sync_end", &self.v_sync_end)?,
+            v_total: timing("v_total", &self.v_total)?,
+            flags: self.flags.clone(),
+        })
+    }
+    /// Vertical refresh rate in Hz implied by the pixel clock and totals
+    pub fn refresh_rate(&self) -> f64 {
+        self.timings().map(|t| t.refresh_rate()).unwrap_or(0.0)
+    }
     /*
     pub fn new_empty() -> CvtMode {
         CvtMode {
@@ -113,206 +243,338 @@ fn delete_mode_xrandr(n: &str, d: Option<Vec<String>>, verbose: bool) -> Result<
 }
 */
 
-pub fn add_mode(w: Option<&str>, h: Option<&str>, r: Option<&str>, d: Option<&str>, n: Option<&str>, t: Option<&str>, f: Option<&str>, test: bool, save: bool, verbose: bool) -> Result<(),Error> {
-    let current_modes = get_current_modes(verbose)?;
-    // Use first current display mode for parameters not supplied
-    // and as the fallback if test option is used
-    let width = w.unwrap_or(&current_modes[0].width).to_string();
-    let height = h.unwrap_or(&current_modes[0].height).to_string();
-    let rate = r.unwrap_or(&current_modes[0].rate).to_string();
-    let display = d.unwrap_or(&current_modes[0].display).to_string();
-    let tmp = format!("{}x{}_{}",width,height,rate);
-    // default test timeout is 10 seconds.
-    let name = match n {
-        Some(nm) => String::from(nm),
-        None => {
-            tmp
+/// What `cathode add` should create. Anything left as None is taken from the current mode of
+/// the display, or from its preferred mode if it is switched off.
+#[derive(Clone,Debug)]
+pub struct AddModeRequest {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub rate: Option<f64>,
+    /// connector or monitor identity; defaults to the first connected display
+    pub display: Option<String>,
+    /// defaults to <width>x<height>_<rate>, with an i after the height for interlaced modes
+    pub name: Option<String>,
+    pub timing: Timing,
+    /// generate interlaced timings, as `cvt -i` does
+    pub interlaced: bool,
+    /// test the mode before saving it, reverting after `timeout` seconds
+    pub test: bool,
+    pub timeout: u64,
+    pub save: bool,
+    /// the modes file to save to instead of the default one
+    pub filename: Option<String>,
+}
+
+impl Default for AddModeRequest {
+    fn default() -> AddModeRequest {
+        AddModeRequest {
+            width: None,
+            height: None,
+            rate: None,
+            display: None,
+            name: None,
+            timing: Timing::Cvt,
+            interlaced: false,
+            test: false,
+            timeout: DEFAULT_TIMEOUT,
+            save: true,
+            filename: None,
+        }
+    }
+}
+
+/// Which saved mode `cathode apply` should apply, and how.
+#[derive(Clone,Debug)]
+pub struct ApplyRequest {
+    pub name: String,
+    /// connectors or monitor identities, or `all` for every connected display; defaults to the
+    /// monitor the mode was created for
+    pub displays: Vec<String>,
+    /// test the mode first, reverting after `timeout` seconds unless it is kept
+    pub test: bool,
+    pub timeout: u64,
+    /// apply the mode whenever `cathode restore` or the daemon runs
+    pub persist: bool,
+    /// the modes file to look the mode up in and persist it to instead of the default one
+    pub filename: Option<String>,
+}
+
+impl ApplyRequest {
+    pub fn new(name: &str) -> ApplyRequest {
+        ApplyRequest {
+            name: name.to_string(),
+            displays: Vec::new(),
+            test: false,
+            timeout: DEFAULT_TIMEOUT,
+            persist: false,
+            filename: None,
         }
+    }
+}
+
+
+pub fn add_mode(backend: &mut dyn DisplayBackend, request: &AddModeRequest, verbose: bool) -> Result<(),Error> {
+    let outputs = backend.outputs(verbose)?;
+    let output = match &request.display {
+        // --display may name the monitor rather than the connector it is plugged into
+        Some(spec) => backend::find_output(spec, &outputs)
+            .ok_or_else(|| Error::DisplayNotFound(format!("Display {} is not connected.", spec)))?,
+        None => outputs.first()
+            .ok_or_else(|| Error::DisplayNotFound(String::from("No displays are connected.")))?,
+    };
+    let display = output.display.clone();
+    // Use the display's current mode, or failing that its preferred one, for parameters not
+    // supplied; it is also the mode to revert to if the test option is used
+    let fallback = output.revert_mode().ok();
+    let missing = |what: &str| Error::InvalidInput(format!("Display {} has no current or preferred mode to take the {} from; pass it explicitly.", display, what));
+    let width = match request.width {
+        Some(w) => w,
+        None => fallback.map(|m| m.width).ok_or_else(|| missing("width"))?,
     };
-    let i_mode = InputMode {
-        width,
-        height,
-        rate,
-        display: String::from(&display),
-        name: name.clone()
+    let height = match request.height {
+        Some(h) => h,
+        None => fallback.map(|m| m.height).ok_or_else(|| missing("height"))?,
+    };
+    let rate = match request.rate {
+        Some(r) => r,
+        None => fallback.map(|m| m.rate).ok_or_else(|| missing("refresh rate"))?,
+    };
+    let monitor = output.identity();
+    let name = match &request.name {
+        Some(nm) => nm.clone(),
+        None => format!("{}x{}{}_{}",width,height,if request.interlaced { "i" } else { "" },rate),
     };
-    let mut d_vec: Vec<String> = Vec::with_capacity(1);
-    d_vec.push(display.clone());
     // compute CVT timings and delete xrandr mode concurrently; wait for deletion before adding to xrandr
-    //let del_handle = thread::spawn(move || delete_mode_xrandr(&name, Some(d_vec), verbose));
-    let cvt_handle = thread::spawn(move || gen_cvt_mode(&i_mode, verbose));
-    let fallback_cvt_handle = thread::spawn(move || gen_cvt_mode(&current_modes[0], verbose));
+    //let del_handle = thread::spawn(move || delete_mode_xrandr(&name, Some(vec![display.clone()]), verbose));
     //let _ = del_handle.join().unwrap();
-    let cvt = cvt_handle.join().unwrap();
-    let fallback_cvt = fallback_cvt_handle.join().unwrap();
-    new_mode(&cvt, &display, verbose)?;
-    if test {
-        test_mode(&cvt, &fallback_cvt, &display, t, verbose)?;
+    let mut cvt = generate(&name, width, height, rate, request.timing, request.interlaced, verbose)?;
+    cvt.monitor = monitor;
+    backend.new_mode(&cvt, &display, verbose)?;
+    if request.test {
+        test(backend, &cvt, &[(&display, output.revert_mode()?)], request.timeout, verbose)?;
+    }
+    if request.save {
+        fileio::save_mode(&cvt,request.filename.as_deref(),verbose)?
     }
-    if save {
-        fileio::save_mode(&cvt,f,verbose)?
+    if output::json() {
+        output::emit(&json!({
+            "mode": cvt,
+            "display": display,
+            "tested": request.test,
+            "saved": request.save,
+        }))?;
     }
     Ok(())
 }
 
 
-pub fn apply_mode(n: &str, d: &str, t: Option<&str>, test: bool, persist: bool, verbose: bool) -> Result<(), io::Error> {
-    println!("Applying mode {} to display {}.",n,d);
-    let mode = fileio::get_mode(n, None, verbose).unwrap();
-    if test {
-        let default_modes = get_default_modes(verbose)?;
-        let default_mode = gen_cvt_mode(&default_modes[0],verbose);
-        test_mode(&mode, &default_mode, d, t, verbose)?;
-        println!("Keep the mode you just tested? y/n");
-        let mut input = String::new();
-        while !(input.contains("y") || input.contains("n")) {
-            let _ = io::stdin().read_line(&mut input);
-            if input.contains("n") {
-                return Ok(());
+pub fn apply_mode(backend: &mut dyn DisplayBackend, request: &ApplyRequest, verbose: bool) -> Result<(), Error> {
+    let n = request.name.as_str();
+    let mode = fileio::get_mode(n, request.filename.as_deref(), verbose)?;
+    let outputs = backend.outputs(verbose)?;
+    let targets = target_outputs(&mode, &request.displays, &outputs)?;
+    let displays: Vec<&str> = targets.iter().map(|o| o.display.as_str()).collect();
+    if let Some(id) = &mode.monitor {
+        for o in &targets {
+            let identity = o.identity();
+            if identity.as_ref() != Some(id) {
+                warn!("Warning: mode {} was created for monitor {}, but display {} is {}.", n, id, o.display, identity.as_ref().map(|i| i.to_string()).unwrap_or_else(|| String::from("an unknown monitor")));
             }
         }
     }
-    switch_mode(n, d, verbose)?;
-    if persist {
-        fileio::save_mode_persistent(&mode, verbose)?;
+    say!("Applying mode {} to display {}.",n,displays.join(", "));
+    for d in &displays {
+        register_mode(backend, &mode, d, verbose)?;
+    }
+    if request.test {
+        // each display goes back to whatever it was running before the test
+        let reverts = targets.iter().map(|o| o.revert_mode().map(|m| (o.display.as_str(), m)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        test(backend, &mode, &reverts, request.timeout, verbose)?;
+        // a dry run carries on as though the answer was yes
+        if !command::dry_run() && !output::confirm("Keep the mode you just tested? y/n") {
+            return applied(n, &displays, false, false);
+        }
+    }
+    let changes: Vec<(&str, ModeRef)> = displays.iter().map(|d| (*d, ModeRef::Custom(&mode))).collect();
+    backend.switch_modes(&changes, verbose)?;
+    if request.persist {
+        for o in &targets {
+            fileio::save_mode_persistent(&mode, &o.display, o.identity(), request.filename.as_deref(), verbose)?;
+        }
+    }
+    applied(n, &displays, true, request.persist)
+}
+
+fn applied(n: &str, displays: &[&str], applied: bool, persisted: bool) -> Result<(), Error> {
+    if output::json() {
+        output::emit(&json!({
+            "mode": n,
+            "display": displays[0],
+            "displays": displays,
+            "applied": applied,
+            "persisted": persisted,
+        }))?;
     }
     Ok(())
 }
 
 
-fn test_mode(mode: &CvtMode, default_mode: &CvtMode, display: &str, t: Option<&str>, verbose: bool) -> Result<(), io::Error> {
-    let name = &mode.get_name();
-    let default_name = &default_mode.get_name();
-    let timeout: u64 = match t {
-        Some(time) => {
-            let tmp = match time.parse() {
-                Ok(kk) => kk,
-                Err(_) => {
-                    eprintln!("Error: timeout must be an integer greater than zero. Using default timeout of 10 seconds.");
-                    10 // just default to 10 secs if invalid timeout provided rather than returning an error
-                }
-            };
-            if tmp > 0 {
-                tmp
-            } else {
-                10 // default to 10 secs if none given
+/// Switches `display` to `mode`, first making the mode known to the backend if it isn't already.
+pub fn apply(backend: &mut dyn DisplayBackend, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+    register_mode(backend, mode, display, verbose)?;
+    backend.switch_mode(mode, display, verbose)
+}
+
+/// Makes a saved mode available on the display again, e.g. after the X server restarted.
+/// It may well still be there from an earlier apply, which is fine.
+pub fn register_mode(backend: &mut dyn DisplayBackend, mode: &CvtMode, display: &str, verbose: bool) -> Result<(), Error> {
+    match backend.new_mode(mode, display, verbose) {
+        Err(Error::ModeExists(_)) => {
+            if verbose {
+                say!("Mode {} is already known to the {} backend.", mode.get_name(), backend.name());
             }
+            Ok(())
         }
-        None => 10
-    };
-    let delay = time::Duration::from_secs(timeout);
-    if verbose {
-        println!("Testing mode {} on display {} for {} secs.", name, display, timeout);
-        thread::sleep(time::Duration::from_secs(1));
+        r => r,
     }
-    if verbose {
-        let _ = thread::spawn(move || util::print_countdown(timeout)); // this should maybe print regardless of verbose option, idk
-    }
-    let handle = thread::spawn(move || thread::sleep(delay));
-    switch_mode(name, display, verbose)?;
-    handle.join().expect("Timer thread had an error.");
-    if verbose {
-        println!("Reverting to mode {} on display {}.", default_name, display);
-    }
-    switch_mode(default_name, display, verbose)?;
-    Ok(())
 }
 
 
-fn gen_cvt_mode(input: &InputMode, verbose: bool) -> CvtMode {
-    if verbose {
-        println!("Generating coordinated video timings for mode {}",input.name);
+// Every display named by --display, in order and without repeats. `all` picks every connected
+// display; with no --display at all the mode goes to the monitor it was created for.
+fn target_outputs<'a>(mode: &CvtMode, displays: &[String], outputs: &'a [Output]) -> Result<Vec<&'a Output>, Error> {
+    if displays.iter().any(|d| d == "all") {
+        if outputs.is_empty() {
+            return Err(Error::DisplayNotFound(String::from("No displays are connected.")));
+        }
+        return Ok(outputs.iter().collect());
     }
-    let mut cmd = process::Command::new("cvt");
-    cmd.arg(&input.width).arg(&input.height).arg(&input.rate);
-    let output = cmd.output().unwrap();
-    let out = str::from_utf8(&output.stdout).unwrap();
-    let lines: Vec<_> = out.split('"').collect();
-    let mut t: Vec<_> = lines[2][2..lines[2].len()-1].split(" ").collect();
-    let mut i=0;
-    while i < t.len() {
-        if t[i] == "" || t[i] == "\t" {
-            t.remove(i);
-        } else {
-            i += 1;
+    if displays.is_empty() {
+        return Ok(vec![target_output(mode, None, outputs)?]);
+    }
+    let mut targets: Vec<&Output> = Vec::new();
+    for d in displays {
+        let o = target_output(mode, Some(d), outputs)?;
+        if !targets.iter().any(|t| t.display == o.display) {
+            targets.push(o);
         }
     }
-    let tmp = CvtMode {
-        name: input.name.to_owned(),
-        clock: String::from(t[0]),
-        h_disp: String::from(t[1]),
-        h_sync_start: String::from(t[2]),
-        h_sync_end: String::from(t[3]),
-        h_total: String::from(t[4]),
-        v_disp: String::from(t[5]),
-        v_sync_start: String::from(t[6]),
-        v_sync_end: String::from(t[7]),
-        v_total: String::from(t[8]),
-        flags: format!("{} {}",t[9],t[10]),
-    };
-    if verbose {
-        println!("{:?}",tmp);
+    Ok(targets)
+}
+
+/// --display may be a connector or a monitor identity; without it, the mode goes to
+/// whichever connector the monitor it was created for is currently on
+pub fn target_output<'a>(mode: &CvtMode, d: Option<&str>, outputs: &'a [Output]) -> Result<&'a Output, Error> {
+    match (d, &mode.monitor) {
+        (Some(spec), _) => backend::find_output(spec, outputs)
+            .ok_or_else(|| Error::DisplayNotFound(format!("Display {} is not connected.", spec))),
+        (None, Some(id)) => backend::find_monitor(id, outputs)
+            .ok_or_else(|| Error::DisplayNotFound(format!("Monitor {} for mode {} is not connected.", id, mode.name))),
+        (None, None) => Err(Error::InvalidInput(format!("Mode {} is not bound to a monitor; specify a display with --display.", mode.name))),
     }
-    tmp
 }
 
 
-// Retrieves modes which are currently in use
-fn get_current_modes(verbose: bool) -> Result<Vec<InputMode>, Error> {
+/// Shows `mode` on each display in `targets` for `timeout` seconds, then switches every one of
+/// them back to the mode paired with it. The mode has to be known to the backend already, e.g.
+/// through [`register_mode`].
+pub fn test(backend: &mut dyn DisplayBackend, mode: &CvtMode, targets: &[(&str, &OutputMode)], timeout: u64, verbose: bool) -> Result<(), Error> {
+    let name = &mode.get_name();
+    let displays = targets.iter().map(|(d, _)| *d).collect::<Vec<_>>().join(", ");
+    let tested: Vec<(&str, ModeRef)> = targets.iter().map(|(d, _)| (*d, ModeRef::Custom(mode))).collect();
+    let reverted: Vec<(&str, ModeRef)> = targets.iter().map(|(d, m)| (*d, ModeRef::Output(m))).collect();
+    // don't blank the displays for the whole timeout if the backend can tell us the mode won't work
+    for (display, _) in targets {
+        backend.check_mode(mode, display, verbose)?;
+    }
+    if command::dry_run() {
+        // nothing is actually shown, so there is nothing to wait for
+        say!("Would test mode {} on display {} for {} secs.", name, displays, timeout);
+        backend.switch_modes(&tested, verbose)?;
+        return backend.switch_modes(&reverted, verbose);
+    }
+    let delay = time::Duration::from_secs(timeout);
     if verbose {
-        println!("Retrieving current display configuration.");
+        say!("Testing mode {} on display {} for {} secs.", name, displays, timeout);
+        thread::sleep(time::Duration::from_secs(1));
     }
-    let re = Regex::new(r"(\S+)\s+connected.*\n[[a-zA-Z0-9\.]*\n]*\s*([0-9]+)x([0-9]+)\s*([0-9]+\.[0-9]+)\*").unwrap();
-    util::get_modes_helper(&re, verbose)
-}
-
-// Retrieves the default modes for each display
-fn get_default_modes(verbose: bool) -> Result<Vec<InputMode>, Error> {
     if verbose {
-        println!("Retrieving current display configuration.");
+        let _ = thread::spawn(move || util::print_countdown(timeout)); // this should maybe print regardless of verbose option, idk
+    }
+    let handle = thread::spawn(move || thread::sleep(delay));
+    let shown = backend.switch_modes(&tested, verbose)
+        .and_then(|()| handle.join().map_err(|_| Error::backend("The test timer thread panicked.")));
+    if verbose {
+        let modes = targets.iter().map(|(d, m)| format!("{} on display {}", m.name, d)).collect::<Vec<_>>().join(", ");
+        say!("Reverting to mode {}.", modes);
     }
-    let re = Regex::new(r"(\S+)\s+connected.*\n[[a-zA-Z0-9\.]*\n]*\s*([0-9]+)x([0-9]+)\s*([0-9]+\.[0-9]+)[\*]?\+").unwrap();
-    util::get_modes_helper(&re, verbose)
+    // put everything back even if only some of the displays switched
+    backend.switch_modes(&reverted, verbose)?;
+    shown
 }
 
 
-fn switch_mode(name: &str, display: &str, verbose: bool) -> Result<(), io::Error> {
-    let mut cmd = process::Command::new("xrandr");
-    cmd.arg("--output").arg(&display).arg("--mode").arg(name);
-    if verbose {
-        println!("Applying mode {} to display {}",name,&display);
+// Prints every connected output and the modes it advertises, marking the current (*) and preferred (+) ones
+pub fn list_outputs(backend: &mut dyn DisplayBackend, verbose: bool) -> Result<(), Error> {
+    let outputs = backend.outputs(verbose)?;
+    let is = |m: &OutputMode, other: &Option<OutputMode>| other.as_ref().map(|o| o.name == m.name && o.rate == m.rate).unwrap_or(false);
+    if output::json() {
+        let outputs: Vec<_> = outputs.iter().map(|o| json!({
+            "display": o.display,
+            "monitor": o.identity().map(|id| id.to_string()),
+            "modes": o.modes.iter().map(|m| json!({
+                "name": m.name,
+                "width": m.width,
+                "height": m.height,
+                "rate": m.rate,
+                "current": is(m, &o.current),
+                "preferred": is(m, &o.preferred),
+            })).collect::<Vec<_>>(),
+        })).collect();
+        return output::emit(&json!({ "outputs": outputs }));
     }
-    cmd.output()?;
-    if verbose {
-        println!("Successfully applied mode {} to display {}",name, &display);
+    for o in &outputs {
+        match o.identity() {
+            Some(id) => out!("{} ({})", o.display, id),
+            None => out!("{}", o.display),
+        }
+        for m in &o.modes {
+            out!("   {:<20} {:>5}x{:<5} {:>7.2}{}{}", m.name, m.width, m.height, m.rate, if is(m, &o.current) { "*" } else { " " }, if is(m, &o.preferred) { "+" } else { "" });
+        }
     }
     Ok(())
 }
 
-// Adds the newly created mode to xrandr
-fn new_mode(mode: &CvtMode, display: &str, verbose: bool) -> Result<(), io::Error> {
-    let mut cmd = process::Command::new("xrandr");
-    cmd.arg("--newmode")
-        .arg(&mode.name)
-        .arg(&mode.clock)
-        .arg(&mode.h_disp)
-        .arg(&mode.h_sync_start)
-        .arg(&mode.h_sync_end)
-        .arg(&mode.h_total)
-        .arg(&mode.v_disp)
-        .arg(&mode.v_sync_start)
-        .arg(&mode.v_sync_end)
-        .arg(&mode.v_total)
-        .arg(&mode.flags);
+
+/// Generates a mode with the given size and refresh rate using cvt. The refresh rate of an
+/// interlaced mode is its field rate.
+pub fn generate(name: &str, width: u32, height: u32, rate: f64, timing: Timing, interlaced: bool, verbose: bool) -> Result<CvtMode, Error> {
+    if interlaced && timing == Timing::CvtReducedBlanking {
+        return Err(Error::InvalidInput(String::from("CVT has no interlaced reduced blanking timings; use the cvt timing for interlaced modes.")));
+    }
     if verbose {
-        println!("Creating xrandr mode {}",&mode.name);
+        say!("Generating coordinated video timings for mode {}",name);
+    }
+    let mut cmd = process::Command::new("cvt");
+    if timing == Timing::CvtReducedBlanking {
+        cmd.arg("-r");
+    }
+    if interlaced {
+        cmd.arg("-i");
     }
-    cmd.output()?;
+    cmd.arg(width.to_string()).arg(height.to_string()).arg(rate.to_string());
+    let output = command::query(&mut cmd)?;
+    let out = String::from_utf8_lossy(&output.stdout);
+    // depending on the problem, cvt explains why it can't produce a mode on stdout or stderr
+    let mut tmp = import::parse_modelines(&out).into_iter().next().ok_or_else(|| {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let said = [stderr.trim(), out.trim()].iter().filter(|s| !s.is_empty()).cloned().collect::<Vec<_>>().join(" ");
+        Error::InvalidTiming(format!("cvt could not compute timings for {}x{} at {} Hz: {}", width, height, rate, if said.is_empty() { "it printed no modeline" } else { &said }))
+    })?;
+    tmp.name = name.to_owned();
     if verbose {
-        println!("Adding mode {} for display {}.",&mode.name,display);
+        say!("{:?}",tmp);
     }
-    cmd = process::Command::new("xrandr");
-    cmd.arg("--addmode").arg(display).arg(&mode.name);
-    cmd.output()?;
-    Ok(())
+    Ok(tmp)
 }
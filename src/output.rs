@@ -0,0 +1,90 @@
+//! How the library reports progress and results.
+//!
+//! Nothing here prints on its own: messages go to the [`Reporter`] a front-end installs with
+//! [`set_reporter`], and are dropped if there is none.
+
+use std::fmt;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool,Ordering};
+use serde::Serialize;
+use crate::error::Error;
+
+
+/// What a message is, so a front-end can decide where it goes.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Channel {
+    /// Progress and informational messages
+    Info,
+    /// Warnings and problems which don't stop the command
+    Warning,
+    /// What the command was asked to produce: tables, scripts, JSON
+    Result,
+}
+
+/// Receives everything the library has to say.
+pub trait Reporter: Send + Sync {
+    /// Shows one message; it does not end with a newline.
+    fn report(&self, channel: Channel, message: &str);
+    /// Asks a yes or no question, such as whether to keep a mode that was just tested.
+    /// Without a reporter the answer is always no, so an untested mode is reverted.
+    fn confirm(&self, question: &str) -> bool;
+}
+
+static REPORTER: OnceLock<Box<dyn Reporter>> = OnceLock::new();
+
+// Set once from --output before any subcommand runs
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Installs the reporter for the rest of the process; later calls are ignored.
+pub fn set_reporter(reporter: Box<dyn Reporter>) {
+    let _ = REPORTER.set(reporter);
+}
+
+/// Makes commands produce one JSON object per line instead of human readable output.
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn report(channel: Channel, args: fmt::Arguments) {
+    if let Some(r) = REPORTER.get() {
+        r.report(channel, &args.to_string());
+    }
+}
+
+// Nobody can answer without a reporter, and keeping a mode no one has seen could leave the
+// display blank, so that counts as no
+pub fn confirm(question: &str) -> bool {
+    REPORTER.get().map(|r| r.confirm(question)).unwrap_or(false)
+}
+
+// Progress and informational messages
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::output::report($crate::output::Channel::Info, format_args!($($arg)*))
+    };
+}
+
+// Warnings, and errors which don't stop the command
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::output::report($crate::output::Channel::Warning, format_args!($($arg)*))
+    };
+}
+
+// The output of a command
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::output::report($crate::output::Channel::Result, format_args!($($arg)*))
+    };
+}
+
+// Writes a result as a single line of JSON
+pub fn emit<T: Serialize>(value: &T) -> Result<(), Error> {
+    let s = serde_json::to_string(value).map_err(|e| Error::InvalidData(e.to_string()))?;
+    out!("{}", s);
+    Ok(())
+}
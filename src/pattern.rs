@@ -0,0 +1,336 @@
+use std::{thread,time};
+use std::result::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::present::{self,CompleteKind,CompleteMode,ConnectionExt as _};
+use x11rb::protocol::randr::{self,ConnectionExt as _};
+use x11rb::protocol::xproto::{ChangeGCAux,ConfigureWindowAux,ConnectionExt as _,CreateGCAux,CreateWindowAux,Gcontext,Pixmap,Rectangle,VisualClass,Window,WindowClass};
+use x11rb::rust_connection::RustConnection;
+use x11rb::COPY_DEPTH_FROM_PARENT;
+use crate::backend::{self,x11,DisplayBackend};
+use crate::backend::x11::x_error;
+use crate::error::Error;
+use crate::{command,fileio,mode,output};
+
+
+/// A fullscreen window on one display showing a test pattern for judging a mode by eye, or by
+/// photographing it with a slow shutter. Every frame is drawn for the frame counter of the
+/// display and presented at its vblank, so a skipped frame shows up as a gap:
+/// - a row of 16 cells, one lit per frame, in turn
+/// - a block moving a fixed distance each frame
+/// - a checkerboard inverting every frame, which looks an even grey unless frames are dropped
+/// - a 256 step grey gradient, for spotting banding
+///
+/// The window follows the display if its mode changes, e.g. while a mode is being tested.
+pub struct Pattern {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl Pattern {
+    /// Opens the window on `display` and starts drawing. With --dry-run nothing is shown.
+    pub fn show(display: &str, verbose: bool) -> Result<Pattern, Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        if command::dry_run() {
+            say!("Would show a test pattern on display {}.", display);
+            return Ok(Pattern { stop, thread: None });
+        }
+        let painter = Painter::open(display)?;
+        if verbose {
+            say!("Showing a test pattern on display {}.", display);
+        }
+        let flag = stop.clone();
+        let thread = thread::spawn(move || painter.run(&flag));
+        Ok(Pattern { stop, thread: Some(thread) })
+    }
+
+    /// Stops drawing and closes the window
+    pub fn close(mut self) -> Result<(), Error> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take() {
+            Some(t) => t.join().unwrap_or_else(|_| Err(Error::backend("The test pattern thread panicked."))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Pattern {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+
+/// Shows the test pattern on a display for `timeout` seconds. Given a saved mode, the display
+/// is switched to it for that time and then reverted, as with `apply --test`; otherwise the
+/// pattern is shown on whatever the display is running.
+pub fn show_pattern(backend: &mut dyn DisplayBackend, name: Option<&str>, display: Option<&str>, timeout: u64, filename: Option<&str>, verbose: bool) -> Result<(), Error> {
+    if backend.name() != "xrandr" {
+        return Err(Error::Unsupported(String::from("The test pattern needs an X server, so it is only available with the xrandr backend.")));
+    }
+    let outputs = backend.outputs(verbose)?;
+    let name = match name {
+        Some(n) => n,
+        None => {
+            let o = match display {
+                Some(d) => backend::find_output(d, &outputs).ok_or_else(|| Error::DisplayNotFound(format!("Display {} is not connected.", d)))?,
+                None => outputs.iter().find(|o| o.current.is_some()).ok_or_else(|| Error::DisplayNotFound(String::from("No displays are running a mode.")))?,
+            };
+            let pattern = Pattern::show(&o.display, verbose)?;
+            if !command::dry_run() {
+                thread::sleep(time::Duration::from_secs(timeout));
+            }
+            pattern.close()?;
+            return shown(&o.display, None, timeout);
+        }
+    };
+    let m = fileio::get_mode(name, filename, verbose)?;
+    let o = mode::target_output(&m, display, &outputs)?;
+    let revert = o.revert_mode()?;
+    mode::register_mode(backend, &m, &o.display, verbose)?;
+    let pattern = Pattern::show(&o.display, verbose)?;
+    let tested = mode::test(backend, &m, &[(&o.display, revert)], timeout, verbose);
+    let closed = pattern.close();
+    tested.and(closed)?;
+    shown(&o.display, Some(name), timeout)
+}
+
+fn shown(display: &str, name: Option<&str>, timeout: u64) -> Result<(), Error> {
+    if output::json() {
+        output::emit(&serde_json::json!({ "display": display, "mode": name, "seconds": timeout }))?;
+    }
+    Ok(())
+}
+
+
+// Cells in the frame counter row
+const CELLS: u64 = 16;
+// Side of a checkerboard square, in pixels
+const SQUARE: u16 = 32;
+
+// A back buffer, which may only be drawn on again once the server has finished showing it
+struct Buffer {
+    pixmap: Pixmap,
+    idle: bool,
+}
+
+// The drawing side, which runs on its own thread with its own connection. Frames are composed
+// off screen and handed to the Present extension for the next vblank, so none are shown half
+// drawn.
+struct Painter {
+    conn: RustConnection,
+    display: String,
+    root: Window,
+    window: Window,
+    gc: Gcontext,
+    crtc: randr::Crtc,
+    width: u16,
+    height: u16,
+    depth: u8,
+    // how to make a pixel value out of 8 bit colour channels for the screen's visual
+    shifts: [u32; 3],
+    // the parts of the pattern that never change, drawn once per size
+    background: Pixmap,
+    buffers: Vec<Buffer>,
+}
+
+impl Painter {
+    fn open(display: &str) -> Result<Painter, Error> {
+        let (conn, screen) = x11::connect()?;
+        let s = conn.setup().roots[screen].clone();
+        let visual = s.allowed_depths.iter().flat_map(|d| d.visuals.iter()).find(|v| v.visual_id == s.root_visual)
+            .filter(|v| v.class == VisualClass::TRUE_COLOR)
+            .ok_or_else(|| Error::Unsupported(String::from("The test pattern needs a true colour X screen.")))?;
+        // the top 8 bits of each channel's mask
+        let shifts = [visual.red_mask, visual.green_mask, visual.blue_mask].map(|m| (32 - m.leading_zeros()).saturating_sub(8));
+        let (crtc, info) = x11::find_crtc(&conn, s.root, display)?;
+        let window = conn.generate_id().map_err(x_error)?;
+        conn.create_window(COPY_DEPTH_FROM_PARENT, window, s.root, info.x, info.y, info.width, info.height, 0, WindowClass::INPUT_OUTPUT, 0,
+            &CreateWindowAux::new().override_redirect(1).background_pixel(s.black_pixel)).map_err(x_error)?;
+        let gc = conn.generate_id().map_err(x_error)?;
+        conn.create_gc(gc, window, &CreateGCAux::new().graphics_exposures(0)).map_err(x_error)?;
+        conn.map_window(window).map_err(x_error)?;
+        // to follow the display when its mode changes
+        conn.randr_select_input(s.root, randr::NotifyMask::CRTC_CHANGE).map_err(x_error)?;
+        let eid = conn.generate_id().map_err(x_error)?;
+        conn.present_select_input(eid, window, present::EventMask::COMPLETE_NOTIFY | present::EventMask::IDLE_NOTIFY).map_err(x_error)?;
+        let mut painter = Painter {
+            conn, display: display.to_string(), root: s.root, window, gc, crtc, width: info.width, height: info.height,
+            depth: s.root_depth, shifts, background: x11rb::NONE, buffers: Vec::new(),
+        };
+        painter.paint_background()?;
+        painter.conn.flush().map_err(x_error)?;
+        Ok(painter)
+    }
+
+    // Presents a frame for every vblank until told to stop
+    fn run(mut self, stop: &AtomicBool) -> Result<(), Error> {
+        // the first frame goes out for the vblank after the one this reports
+        self.conn.present_notify_msc(self.window, 0, 0, 1, 0).map_err(x_error)?;
+        self.conn.flush().map_err(x_error)?;
+        let mut first = None;
+        let mut pending = None;
+        let mut late = 0;
+        while !stop.load(Ordering::Relaxed) {
+            match self.conn.poll_for_event().map_err(x_error)? {
+                Some(Event::PresentCompleteNotify(e)) if e.window == self.window => {
+                    if e.kind == CompleteKind::PIXMAP {
+                        if e.mode == CompleteMode::SKIP || pending.map(|t| e.msc > t).unwrap_or(false) {
+                            late += 1;
+                        }
+                    } else if first.is_some() {
+                        // only the first frame is started off by a notification
+                        continue;
+                    }
+                    let target = e.msc + 1;
+                    // the frame counter can start over when the display's mode changes
+                    if first.map(|f| target < f).unwrap_or(true) {
+                        first = Some(target);
+                    }
+                    self.present(target - first.unwrap_or(target), target)?;
+                    pending = Some(target);
+                }
+                Some(Event::PresentIdleNotify(e)) => {
+                    if let Some(b) = self.buffers.iter_mut().find(|b| b.pixmap == e.pixmap) {
+                        b.idle = true;
+                    }
+                }
+                Some(Event::RandrNotify(_)) => {
+                    if self.follow()? {
+                        // a frame on its way at the old size may land late, through no fault of the display
+                        pending = None;
+                    }
+                }
+                Some(_) => (),
+                // polling rather than blocking so a stop request is seen even if the display goes quiet
+                None => thread::sleep(time::Duration::from_millis(1)),
+            }
+        }
+        self.free_pixmaps()?;
+        self.conn.destroy_window(self.window).map_err(x_error)?;
+        self.conn.flush().map_err(x_error)?;
+        if late > 0 {
+            warn!("{} frames of the test pattern were not ready in time, so gaps in it may not be the display's fault.", late);
+        }
+        Ok(())
+    }
+
+    // Moves and resizes the window to cover the display again after its mode changed.
+    // Returns whether anything changed.
+    fn follow(&mut self) -> Result<bool, Error> {
+        let (crtc, info) = match x11::find_crtc(&self.conn, self.root, &self.display) {
+            Ok(c) => c,
+            // switched off for the moment, e.g. halfway through a mode change
+            Err(_) => return Ok(false),
+        };
+        if (crtc, info.width, info.height) == (self.crtc, self.width, self.height) {
+            return Ok(false);
+        }
+        self.crtc = crtc;
+        self.width = info.width;
+        self.height = info.height;
+        let geometry = ConfigureWindowAux::new().x(i32::from(info.x)).y(i32::from(info.y)).width(u32::from(info.width)).height(u32::from(info.height));
+        self.conn.configure_window(self.window, &geometry).map_err(x_error)?;
+        // the server keeps any pixmap still being shown until it is done with it
+        self.free_pixmaps()?;
+        self.paint_background()?;
+        Ok(true)
+    }
+
+    fn create_pixmap(&self) -> Result<Pixmap, Error> {
+        let pixmap = self.conn.generate_id().map_err(x_error)?;
+        self.conn.create_pixmap(self.depth, pixmap, self.window, self.width, self.height).map_err(x_error)?;
+        Ok(pixmap)
+    }
+
+    fn free_pixmaps(&mut self) -> Result<(), Error> {
+        for b in self.buffers.drain(..) {
+            self.conn.free_pixmap(b.pixmap).map_err(x_error)?;
+        }
+        if self.background != x11rb::NONE {
+            self.conn.free_pixmap(self.background).map_err(x_error)?;
+            self.background = x11rb::NONE;
+        }
+        Ok(())
+    }
+
+    fn grey(&self, level: u8) -> u32 {
+        self.shifts.iter().fold(0, |pixel, shift| pixel | (u32::from(level) << shift))
+    }
+
+    fn fill(&self, target: Pixmap, level: u8, rects: &[Rectangle]) -> Result<(), Error> {
+        self.conn.change_gc(self.gc, &ChangeGCAux::new().foreground(self.grey(level))).map_err(x_error)?;
+        self.conn.poly_fill_rectangle(target, self.gc, rects).map_err(x_error)?;
+        Ok(())
+    }
+
+    // Draws the black ground and the grey gradient, which every frame starts from
+    fn paint_background(&mut self) -> Result<(), Error> {
+        let background = self.create_pixmap()?;
+        let (w, h) = (u32::from(self.width), self.height);
+        let third = h / 3;
+        self.fill(background, 0, &[rect(0, 0, w, h)])?;
+        for level in 0..=255u32 {
+            let x0 = level * w / 256;
+            let x1 = (level + 1) * w / 256;
+            self.fill(background, level as u8, &[rect(x0, 2 * third, x1 - x0, h - 2 * third)])?;
+        }
+        self.background = background;
+        Ok(())
+    }
+
+    // Composes frame number `frame` into a free back buffer and queues it for vblank `msc`
+    fn present(&mut self, frame: u64, msc: u64) -> Result<(), Error> {
+        let index = match self.buffers.iter().position(|b| b.idle) {
+            Some(i) => i,
+            None => {
+                let pixmap = self.create_pixmap()?;
+                self.buffers.push(Buffer { pixmap, idle: true });
+                self.buffers.len() - 1
+            }
+        };
+        let pixmap = self.buffers[index].pixmap;
+        self.draw(pixmap, frame)?;
+        self.conn.present_pixmap(self.window, pixmap, 0, x11rb::NONE, x11rb::NONE, 0, 0, x11rb::NONE, x11rb::NONE, x11rb::NONE,
+            u32::from(present::Option::NONE), msc, 0, 0, &[]).map_err(x_error)?;
+        self.conn.flush().map_err(x_error)?;
+        self.buffers[index].idle = false;
+        Ok(())
+    }
+
+    fn draw(&self, target: Pixmap, frame: u64) -> Result<(), Error> {
+        let (w, h) = (self.width, self.height);
+        let third = h / 3;
+        let row = third / 4;
+        self.conn.copy_area(self.background, target, self.gc, 0, 0, 0, 0, w, h).map_err(x_error)?;
+
+        // frame counter: one cell lit per frame
+        let cell = u32::from(w) / CELLS as u32;
+        let cells: Vec<Rectangle> = (0..CELLS as u32).map(|i| rect(i * cell + 2, 2, cell.saturating_sub(4), row.saturating_sub(4))).collect();
+        self.fill(target, 48, &cells)?;
+        self.fill(target, 255, &cells[(frame % CELLS) as usize..][..1])?;
+
+        // moving block: a fixed step per frame, so a photo shows evenly spaced copies
+        let side = third - row;
+        let step = u64::from(side / 4).max(1);
+        let x = (frame * step % u64::from(w)) as u32;
+        self.fill(target, 255, &[rect(x, row, u32::from(side), side)])?;
+
+        // checkerboard inverting every frame
+        let squares: Vec<Rectangle> = (0..third / SQUARE).flat_map(|r| (0..w / SQUARE).map(move |c| (r, c)))
+            .filter(|(r, c)| (u64::from(r + c) + frame).is_multiple_of(2))
+            .map(|(r, c)| rect(u32::from(c * SQUARE), third + r * SQUARE, u32::from(SQUARE), SQUARE))
+            .collect();
+        self.fill(target, 255, &squares)?;
+        Ok(())
+    }
+}
+
+fn rect(x: u32, y: u16, width: u32, height: u16) -> Rectangle {
+    Rectangle { x: x as i16, y: y as i16, width: width as u16, height }
+}
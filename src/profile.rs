@@ -0,0 +1,153 @@
+use std::result::Result;
+use serde::{Serialize,Deserialize};
+use crate::{edid,fileio,mode,output,util};
+use crate::backend::{self,DisplayBackend,Output};
+use crate::error::Error;
+
+
+// A mode assigned to a display, either as part of a profile or persisted with `apply --persist`.
+// If the monitor is known, the assignment follows it to whichever connector it is plugged into.
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct Assignment {
+    pub(crate) display: String,
+    pub(crate) mode: String,
+    // the refresh rate of the mode, since a display often has several modes with the same name
+    // (1920x1080 at 60 and 144 Hz); missing from assignments saved by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) monitor: Option<edid::MonitorId>,
+}
+
+// Refresh rates closer than this are the same mode; xrandr rounds them to two places
+const RATE_TOLERANCE: f64 = 0.05;
+
+impl Assignment {
+    pub fn new(display: &str, mode: &str, rate: Option<f64>, monitor: Option<edid::MonitorId>) -> Assignment {
+        Assignment {
+            display: display.to_string(),
+            mode: mode.to_string(),
+            rate,
+            monitor,
+        }
+    }
+    // Returns the assignment with its display set to the connector its monitor is currently on,
+    // or None if that monitor (or, without an identity, that connector) is not connected
+    fn resolve(&self, outputs: &[Output]) -> Option<Assignment> {
+        let output = match &self.monitor {
+            Some(id) => backend::find_monitor(id, outputs)?,
+            None => outputs.iter().find(|o| o.display == self.display)?,
+        };
+        Some(Assignment {
+            display: output.display.clone(),
+            ..self.clone()
+        })
+    }
+}
+
+// The modes to apply when exactly the monitors in `fingerprint` are connected
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct Profile {
+    pub(crate) name: String,
+    pub(crate) fingerprint: Vec<String>,
+    pub(crate) outputs: Vec<Assignment>,
+}
+
+
+// Identifies the set of connected monitors by connector and EDID hash, so the same
+// monitors plugged into the same ports always produce the same fingerprint.
+pub fn fingerprint(outputs: &[Output]) -> Vec<String> {
+    let mut fp: Vec<String> = outputs.iter().map(|o| {
+        match (&o.edid, &o.description) {
+            (Some(edid), _) => format!("{}:{:016x}", o.display, util::fnv1a(edid)),
+            (None, Some(d)) => format!("{}:{:016x}", o.display, util::fnv1a(d.as_bytes())),
+            (None, None) => format!("{}:unknown", o.display),
+        }
+    }).collect();
+    fp.sort();
+    fp
+}
+
+pub fn save_profile(backend: &mut dyn DisplayBackend, name: &str, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let outputs = backend.outputs(verbose)?;
+    let mut assignments: Vec<Assignment> = Vec::with_capacity(outputs.len());
+    for o in &outputs {
+        if let Some(m) = &o.current {
+            assignments.push(Assignment::new(&o.display, &m.name, Some(m.rate), o.identity()));
+        }
+    }
+    let displays: Vec<String> = assignments.iter().map(|a| a.display.clone()).collect();
+    let profile = Profile {
+        name: name.to_string(),
+        fingerprint: fingerprint(&outputs),
+        outputs: assignments,
+    };
+    say!("Saving profile {} for {} connected display(s).", name, outputs.len());
+    if verbose {
+        say!("{:?}", profile);
+    }
+    fileio::update_config(f, verbose, |config| {
+        config.add_profile(profile);
+        Ok(())
+    })?;
+    if output::json() {
+        output::emit(&serde_json::json!({ "profile": name, "displays": displays }))?;
+    }
+    Ok(())
+}
+
+// Applies the profile matching the connected monitors, falling back to the persisted
+// modes of whichever displays are connected. Returns false if there was nothing to apply.
+pub fn restore(backend: &mut dyn DisplayBackend, f: Option<&str>, verbose: bool) -> Result<bool, Error> {
+    let outputs = backend.outputs(verbose)?;
+    let restored = restore_outputs(backend, &outputs, f, verbose)?;
+    if output::json() {
+        output::emit(&serde_json::json!({ "restored": restored }))?;
+    }
+    Ok(restored)
+}
+
+pub fn restore_outputs(backend: &mut dyn DisplayBackend, outputs: &[Output], f: Option<&str>, verbose: bool) -> Result<bool, Error> {
+    let config = fileio::load_config(f, verbose)?;
+    let fp = fingerprint(outputs);
+    let assignments: Vec<Assignment> = match config.profiles.iter().find(|p| p.fingerprint == fp) {
+        Some(p) => {
+            say!("Restoring profile {}.", p.name);
+            p.outputs.clone()
+        }
+        None => {
+            if verbose {
+                say!("No profile matches the connected displays; restoring persisted modes.");
+            }
+            config.persisted.iter()
+                .filter_map(|a| a.resolve(outputs))
+                .collect()
+        }
+    };
+    if assignments.is_empty() {
+        if verbose {
+            say!("Nothing to restore.");
+        }
+        return Ok(false);
+    }
+    for a in assignments {
+        say!("Applying mode {} to display {}.", a.mode, a.display);
+        // saved modes have to be registered with the backend again after a restart or hotplug
+        if let Some(m) = config.get_mode(&a.mode) {
+            mode::apply(backend, m, &a.display, verbose)?;
+            continue;
+        }
+        let existing = outputs.iter()
+            .filter(|o| o.display == a.display)
+            .flat_map(|o| o.modes.iter())
+            .find(|m| m.name == a.mode && a.rate.map(|r| (m.rate - r).abs() < RATE_TOLERANCE).unwrap_or(true));
+        match existing {
+            Some(m) => backend.set_output_mode(m, &a.display, verbose)?,
+            None => match a.rate {
+                Some(r) => warn!("Error: display {} has no mode {} at {:.2} Hz; skipping.", a.display, a.mode, r),
+                None => warn!("Error: display {} has no mode {}; skipping.", a.display, a.mode),
+            },
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,74 @@
+use std::result::Result;
+use serde_json::json;
+use crate::{fileio,output};
+use crate::backend::{DisplayBackend,OutputStatus};
+use crate::error::Error;
+use crate::mode::CvtMode;
+
+
+// Shows what every output is running right now, and which saved mode that is if any
+pub fn print_status(backend: &mut dyn DisplayBackend, f: Option<&str>, verbose: bool) -> Result<(), Error> {
+    let statuses = backend.status(verbose)?;
+    let saved = fileio::import_all_modes(f, verbose)?;
+    if output::json() {
+        let outputs: Vec<_> = statuses.iter().map(|s| {
+            let current = s.output.current.as_ref();
+            json!({
+                "display": s.output.display,
+                "connected": s.connected,
+                "monitor": s.output.identity().map(|id| id.to_string()),
+                "mode": current.map(|m| &m.name),
+                "width": current.map(|m| m.width),
+                "height": current.map(|m| m.height),
+                "refresh": current.map(|m| m.rate),
+                "timings": s.timings,
+                "position": s.position.map(|(x, y)| json!({ "x": x, "y": y })),
+                "rotation": s.rotation,
+                "saved": saved_mode(s, &saved).map(|m| m.get_name()),
+            })
+        }).collect();
+        return output::emit(&json!({ "outputs": outputs }));
+    }
+    for s in &statuses {
+        let o = &s.output;
+        let state = if s.connected { "connected" } else { "disconnected" };
+        match o.identity() {
+            Some(id) => out!("{} ({}) {}", o.display, id, state),
+            None => out!("{} {}", o.display, state),
+        }
+        let m = match &o.current {
+            Some(m) => m,
+            None => {
+                if s.connected {
+                    out!("    disabled");
+                }
+                continue;
+            }
+        };
+        match saved_mode(s, &saved) {
+            Some(sm) if sm.get_name() == m.name => out!("    mode      {} (saved)", m.name),
+            Some(sm) => out!("    mode      {} (saved as {})", m.name, sm.get_name()),
+            None => out!("    mode      {}", m.name),
+        }
+        if let Some(t) = &s.timings {
+            out!("    timing    {:.2} MHz  {} {} {} {}  {} {} {} {}  {}", t.clock, t.h_disp, t.h_sync_start, t.h_sync_end, t.h_total, t.v_disp, t.v_sync_start, t.v_sync_end, t.v_total, t.flags.join(" "));
+        }
+        out!("    refresh   {:.2} Hz", m.rate);
+        if let Some((x, y)) = s.position {
+            out!("    position  {},{}", x, y);
+        }
+        if let Some(r) = &s.rotation {
+            out!("    rotation  {}", r);
+        }
+    }
+    Ok(())
+}
+
+// Matches on the exact timings where the backend reports them, otherwise on size and refresh rate
+fn saved_mode<'a>(s: &OutputStatus, saved: &'a [CvtMode]) -> Option<&'a CvtMode> {
+    let current = s.output.current.as_ref()?;
+    saved.iter().find(|m| match (&s.timings, m.timings()) {
+        (Some(t), Ok(st)) => t.same_as(&st),
+        _ => m.width() == current.width && m.height() == current.height && (m.refresh_rate() - current.rate).abs() < 0.05,
+    })
+}
@@ -0,0 +1,557 @@
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration,Instant};
+use std::result::Result;
+use crossterm::event::{self,Event,KeyCode,KeyEvent,KeyEventKind,KeyModifiers};
+use ratatui::{DefaultTerminal,Frame};
+use ratatui::layout::{Constraint,Layout};
+use ratatui::style::{Color,Modifier,Style};
+use ratatui::text::{Line,Span};
+use ratatui::widgets::{Block,Borders,Paragraph};
+use cathode::{fileio,mode,output};
+use cathode::backend::{DisplayBackend,ModeRef,Output};
+use cathode::error::Error;
+use cathode::output::Channel;
+use cathode::mode::{CvtMode,Timing,Timings};
+
+
+// While the TUI is up, whatever the library reports is kept here and shown in its log pane
+// rather than printed over the screen
+static LOG: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+// Takes a message for the log pane if the TUI is running; false means print it as usual
+pub fn capture(message: &str) -> bool {
+    match LOG.lock().as_mut().map(|log| log.as_mut()) {
+        Ok(Some(log)) => {
+            log.push(message.to_string());
+            true
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Field {
+    Refresh,
+    Clock,
+    HDisp,
+    HSyncStart,
+    HSyncEnd,
+    HTotal,
+    HBlank,
+    VDisp,
+    VSyncStart,
+    VSyncEnd,
+    VTotal,
+    VBlank,
+}
+
+const FIELDS: [(Field, &str); 12] = [
+    (Field::Refresh, "refresh rate"),
+    (Field::Clock, "pixel clock"),
+    (Field::HDisp, "h active"),
+    (Field::HSyncStart, "h sync start"),
+    (Field::HSyncEnd, "h sync end"),
+    (Field::HTotal, "h total"),
+    (Field::HBlank, "h blanking"),
+    (Field::VDisp, "v active"),
+    (Field::VSyncStart, "v sync start"),
+    (Field::VSyncEnd, "v sync end"),
+    (Field::VTotal, "v total"),
+    (Field::VBlank, "v blanking"),
+];
+
+fn value(t: &Timings, field: Field) -> String {
+    match field {
+        Field::Refresh => format!("{:.3} Hz", t.refresh_rate()),
+        Field::Clock => format!("{:.3} MHz", t.clock),
+        Field::HDisp => t.h_disp.to_string(),
+        Field::HSyncStart => t.h_sync_start.to_string(),
+        Field::HSyncEnd => t.h_sync_end.to_string(),
+        Field::HTotal => t.h_total.to_string(),
+        Field::HBlank => t.h_total.saturating_sub(t.h_disp).to_string(),
+        Field::VDisp => t.v_disp.to_string(),
+        Field::VSyncStart => t.v_sync_start.to_string(),
+        Field::VSyncEnd => t.v_sync_end.to_string(),
+        Field::VTotal => t.v_total.to_string(),
+        Field::VBlank => t.v_total.saturating_sub(t.v_disp).to_string(),
+    }
+}
+
+// Moves a field by `steps` of its smallest sensible increment. Changing the refresh rate scales
+// the pixel clock; changing the blanking moves the total and scales the clock to keep the
+// refresh rate, which is how blanking is usually traded for bandwidth. Every other field is
+// changed on its own, so the refresh rate follows from it.
+fn adjust(t: &mut Timings, field: Field, steps: i32) {
+    let nudge = |v: &mut u32| *v = (i64::from(*v) + i64::from(steps)).max(0) as u32;
+    match field {
+        Field::Refresh => {
+            let rate = (t.refresh_rate() + 0.1 * f64::from(steps)).max(1.0);
+            t.set_refresh_rate(rate);
+        }
+        Field::Clock => t.clock = ((t.clock + 0.01 * f64::from(steps)) * 1000.0).round().max(1.0) / 1000.0,
+        Field::HDisp => nudge(&mut t.h_disp),
+        Field::HSyncStart => nudge(&mut t.h_sync_start),
+        Field::HSyncEnd => nudge(&mut t.h_sync_end),
+        Field::HTotal => nudge(&mut t.h_total),
+        Field::VDisp => nudge(&mut t.v_disp),
+        Field::VSyncStart => nudge(&mut t.v_sync_start),
+        Field::VSyncEnd => nudge(&mut t.v_sync_end),
+        Field::VTotal => nudge(&mut t.v_total),
+        Field::HBlank | Field::VBlank => {
+            let rate = t.refresh_rate();
+            nudge(if field == Field::HBlank { &mut t.h_total } else { &mut t.v_total });
+            t.set_refresh_rate(rate);
+        }
+    }
+}
+
+// Roughly the highest pixel clock each kind of connector carries at 8 bits per colour, going
+// by its name. Cards, cables and monitors may well support less.
+fn link_limit(display: &str) -> Option<(&'static str, f64)> {
+    let d = display.to_ascii_uppercase();
+    if d.starts_with("DP") || d.starts_with("EDP") || d.starts_with("DISPLAYPORT") {
+        Some(("DisplayPort HBR2", 720.0))
+    } else if d.starts_with("HDMI") {
+        Some(("HDMI 2.0", 600.0))
+    } else if d.starts_with("DVI") {
+        Some(("dual-link DVI", 330.0))
+    } else if d.starts_with("VGA") {
+        Some(("VGA", 400.0))
+    } else {
+        None
+    }
+}
+
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Pane {
+    Outputs,
+    Saved,
+    Timings,
+}
+
+struct Tuner<'a> {
+    backend: &'a mut dyn DisplayBackend,
+    filename: Option<&'a str>,
+    timeout: u64,
+    verbose: bool,
+    outputs: Vec<Output>,
+    saved: Vec<CvtMode>,
+    pane: Pane,
+    output: usize,
+    saved_mode: usize,
+    field: usize,
+    candidate: Option<Timings>,
+    name: String,
+    // Some while the name is being typed
+    editing: Option<String>,
+    // test modes get their own names, since the display server keeps a mode's first timings
+    tests: u32,
+    // test modes the backend still has, by display; they are removed again once out of use
+    registered: Vec<(String, CvtMode)>,
+    // the test mode last kept, which takes the name it is saved under
+    kept: Option<(String, CvtMode)>,
+    status: Result<String, String>,
+    log: Vec<String>,
+}
+
+// Runs `cathode tune` until the user quits
+pub fn run(backend: &mut dyn DisplayBackend, filename: Option<&str>, timeout: u64, verbose: bool) -> Result<(), Error> {
+    if output::json() {
+        return Err(Error::InvalidInput(String::from("tune is interactive and has no JSON output.")));
+    }
+    let outputs = backend.outputs(verbose)?;
+    if outputs.is_empty() {
+        return Err(Error::DisplayNotFound(String::from("No displays are connected.")));
+    }
+    let saved = fileio::import_all_modes(filename, verbose)?;
+    let mut tuner = Tuner {
+        backend,
+        filename,
+        timeout,
+        verbose,
+        outputs,
+        saved,
+        pane: Pane::Outputs,
+        output: 0,
+        saved_mode: 0,
+        field: 0,
+        candidate: None,
+        name: String::new(),
+        editing: None,
+        tests: 0,
+        registered: Vec::new(),
+        kept: None,
+        status: Ok(String::from("Tab switches panes, Enter loads a display's mode or a saved mode.")),
+        log: Vec::new(),
+    };
+    tuner.load_output();
+    if let Ok(mut log) = LOG.lock() {
+        *log = Some(Vec::new());
+    }
+    let result = ratatui::try_init().map_err(Error::from).and_then(|mut terminal| {
+        let r = tuner.event_loop(&mut terminal);
+        ratatui::restore();
+        r
+    });
+    if let Ok(mut log) = LOG.lock() {
+        *log = None;
+    }
+    result.and(tuner.clean_up())
+}
+
+impl<'a> Tuner<'a> {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Error> {
+        loop {
+            self.draw(terminal, None)?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.key(key, terminal)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Handles a key press; false means quit
+    fn key(&mut self, key: KeyEvent, terminal: &mut DefaultTerminal) -> Result<bool, Error> {
+        if let Some(name) = self.editing.as_mut() {
+            match key.code {
+                KeyCode::Enter => {
+                    if !name.is_empty() {
+                        self.name = name.clone();
+                    }
+                    self.editing = None;
+                }
+                KeyCode::Esc => self.editing = None,
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Char(c) if !c.is_whitespace() => name.push(c),
+                _ => (),
+            }
+            return Ok(true);
+        }
+        let steps = if key.modifiers.contains(KeyModifiers::SHIFT) { 10 } else { 1 };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Tab => self.pane = match self.pane {
+                Pane::Outputs => Pane::Saved,
+                Pane::Saved => Pane::Timings,
+                Pane::Timings => Pane::Outputs,
+            },
+            KeyCode::BackTab => self.pane = match self.pane {
+                Pane::Outputs => Pane::Timings,
+                Pane::Saved => Pane::Outputs,
+                Pane::Timings => Pane::Saved,
+            },
+            KeyCode::Up | KeyCode::Down => {
+                let (selected, len) = match self.pane {
+                    Pane::Outputs => (&mut self.output, self.outputs.len()),
+                    Pane::Saved => (&mut self.saved_mode, self.saved.len()),
+                    Pane::Timings => (&mut self.field, FIELDS.len()),
+                };
+                if len > 0 {
+                    *selected = if key.code == KeyCode::Up { (*selected + len - 1) % len } else { (*selected + 1) % len };
+                }
+            }
+            KeyCode::Enter => match self.pane {
+                Pane::Outputs => self.load_output(),
+                Pane::Saved => self.load_saved(),
+                Pane::Timings => (),
+            },
+            KeyCode::Left | KeyCode::Right | KeyCode::Char('-') | KeyCode::Char('+') | KeyCode::Char('=') => {
+                let steps = if matches!(key.code, KeyCode::Left | KeyCode::Char('-')) { -steps } else { steps };
+                if let Some(t) = self.candidate.as_mut() {
+                    adjust(t, FIELDS[self.field].0, steps);
+                }
+            }
+            KeyCode::Char('c') => self.regenerate(Timing::Cvt),
+            KeyCode::Char('r') => self.regenerate(Timing::CvtReducedBlanking),
+            KeyCode::Char('n') => self.editing = Some(self.name.clone()),
+            KeyCode::Char('t') => {
+                let tested = self.test(terminal);
+                self.report(tested);
+            }
+            KeyCode::Char('s') => {
+                let saved = self.save();
+                self.report(saved);
+            }
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    fn report(&mut self, result: Result<String, Error>) {
+        self.status = result.map_err(|e| e.to_string());
+    }
+
+    // Starts from the mode the selected display is running
+    fn load_output(&mut self) {
+        let o = &self.outputs[self.output];
+        let display = o.display.clone();
+        let current = o.current.clone().or_else(|| o.preferred.clone());
+        let timings = self.backend.status(self.verbose).ok()
+            .and_then(|s| s.into_iter().find(|s| s.output.display == display))
+            .and_then(|s| s.timings);
+        let loaded = match (timings, current) {
+            (Some(t), Some(m)) => {
+                self.name = format!("{}x{}_{:.2}", m.width, m.height, m.rate);
+                Ok(t)
+            }
+            // not every backend reports the exact timings, so fall back to what cvt makes of the mode
+            (None, Some(m)) => {
+                self.name = format!("{}x{}_{:.2}", m.width, m.height, m.rate);
+                mode::generate(&self.name, m.width, m.height, m.rate, Timing::Cvt, false, self.verbose).and_then(|c| c.timings())
+            }
+            (_, None) => Err(Error::InvalidInput(format!("Display {} has no current or preferred mode.", display))),
+        };
+        match loaded {
+            Ok(t) => {
+                self.candidate = Some(t);
+                self.status = Ok(format!("Loaded the mode display {} is running.", display));
+            }
+            Err(e) => self.status = Err(e.to_string()),
+        }
+    }
+
+    fn load_saved(&mut self) {
+        let Some(m) = self.saved.get(self.saved_mode) else { return };
+        match m.timings() {
+            Ok(t) => {
+                self.name = m.get_name().to_string();
+                self.candidate = Some(t);
+                self.status = Ok(format!("Loaded saved mode {}.", m.get_name()));
+            }
+            Err(e) => self.status = Err(e.to_string()),
+        }
+    }
+
+    // Replaces the candidate with cvt's timings for its size and refresh rate
+    fn regenerate(&mut self, timing: Timing) {
+        let Some(t) = &self.candidate else { return };
+        let generated = mode::generate(&self.name, t.h_disp, t.v_disp, (t.refresh_rate() * 1000.0).round() / 1000.0, timing, t.has_flag("interlace") && timing == Timing::Cvt, self.verbose)
+            .and_then(|c| c.timings());
+        match generated {
+            Ok(t) => {
+                self.candidate = Some(t);
+                self.status = Ok(format!("Generated {} timings.", if timing == Timing::Cvt { "CVT" } else { "CVT reduced blanking" }));
+            }
+            Err(e) => self.status = Err(e.to_string()),
+        }
+    }
+
+    // Shows the candidate on the selected display until the timeout runs out or a key is
+    // pressed, then reverts unless the key was y
+    fn test(&mut self, terminal: &mut DefaultTerminal) -> Result<String, Error> {
+        let t = self.candidate.clone().ok_or_else(|| Error::InvalidInput(String::from("Load a mode first.")))?;
+        t.check_order()?;
+        let o = self.outputs[self.output].clone();
+        let revert = o.revert_mode()?.clone();
+        self.tests += 1;
+        // the pid keeps a test mode left running by an earlier session from being mistaken for this one
+        let candidate = CvtMode::from_timings(&format!("cathode-tune-{}-{}", process::id(), self.tests), &t);
+        // a mode which already exists has timings of its own, so it can't stand in for the candidate
+        self.backend.new_mode(&candidate, &o.display, self.verbose)?;
+        self.registered.push((o.display.clone(), candidate.clone()));
+        self.backend.check_mode(&candidate, &o.display, self.verbose)?;
+        self.backend.switch_modes(&[(&o.display, ModeRef::Custom(&candidate))], self.verbose)?;
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let mut keep = false;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            let countdown = format!("Testing on {}; reverting in {} secs. Press y to keep the mode, any other key to revert now.", o.display, left.as_secs() + 1);
+            self.draw(terminal, Some(&countdown))?;
+            if event::poll(left.min(Duration::from_millis(250)))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        keep = key.code == KeyCode::Char('y');
+                        break;
+                    }
+                }
+            }
+        }
+        if keep {
+            self.outputs = self.backend.outputs(self.verbose)?;
+            self.kept = Some((o.display.clone(), candidate));
+            return Ok(format!("Kept the mode on display {}; press s to save it.", o.display));
+        }
+        self.backend.switch_modes(&[(&o.display, ModeRef::Output(&revert))], self.verbose)?;
+        self.remove(&o.display, &candidate)?;
+        Ok(format!("Reverted display {} to {}.", o.display, revert.name))
+    }
+
+    fn remove(&mut self, display: &str, mode: &CvtMode) -> Result<(), Error> {
+        self.backend.remove_mode(mode, display, self.verbose)?;
+        self.registered.retain(|(d, m)| !(d == display && m.get_name() == mode.get_name()));
+        Ok(())
+    }
+
+    // Switches the display from the kept test mode to the same timings under the name they
+    // were saved as, so the test mode can go. Only X keeps modes by name. Returns a note for
+    // the status line if the test mode has to stay.
+    fn rename_kept(&mut self, saved: &CvtMode) -> Result<Option<String>, Error> {
+        let Some((display, kept)) = self.kept.take() else { return Ok(None) };
+        if self.backend.name() != "xrandr" || !kept.timings()?.same_as(&saved.timings()?) {
+            self.kept = Some((display, kept));
+            return Ok(None);
+        }
+        match self.backend.new_mode(saved, &display, self.verbose) {
+            // the X server's mode of that name may well have other timings, so don't switch to it
+            Err(Error::ModeExists(_)) => {
+                let note = format!("display {} stays on {}, since the X server already has a mode named {}", display, kept.get_name(), saved.get_name());
+                self.kept = Some((display, kept));
+                return Ok(Some(note));
+            }
+            r => r?,
+        }
+        self.backend.switch_modes(&[(&display, ModeRef::Custom(saved))], self.verbose)?;
+        self.remove(&display, &kept)?;
+        self.outputs = self.backend.outputs(self.verbose)?;
+        Ok(None)
+    }
+
+    // Removes every test mode left behind, other than one still on screen
+    fn clean_up(&mut self) -> Result<(), Error> {
+        if self.registered.is_empty() {
+            return Ok(());
+        }
+        let outputs = self.backend.outputs(self.verbose)?;
+        for (display, m) in self.registered.clone() {
+            let running = outputs.iter().any(|o| o.display == display && o.current.as_ref().map(|c| c.name == m.get_name()).unwrap_or(false));
+            if running {
+                output::report(Channel::Warning, format_args!("Display {} is still running test mode {}, which was not saved.", display, m.get_name()));
+                continue;
+            }
+            if let Err(e) = self.remove(&display, &m) {
+                output::report(Channel::Warning, format_args!("Could not remove test mode {}: {}", m.get_name(), e));
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<String, Error> {
+        let t = self.candidate.as_ref().ok_or_else(|| Error::InvalidInput(String::from("Load a mode first.")))?;
+        t.check_order()?;
+        let mut m = CvtMode::from_timings(&self.name, t);
+        m.set_monitor(self.outputs[self.output].identity());
+        fileio::save_mode(&m, self.filename, self.verbose)?;
+        self.saved = fileio::import_all_modes(self.filename, self.verbose)?;
+        match self.rename_kept(&m)? {
+            Some(note) => Ok(format!("Saved mode {}; {}.", self.name, note)),
+            None => Ok(format!("Saved mode {}.", self.name)),
+        }
+    }
+
+    fn draw(&mut self, terminal: &mut DefaultTerminal, countdown: Option<&str>) -> Result<(), Error> {
+        if let Ok(mut log) = LOG.lock() {
+            if let Some(l) = log.as_mut() {
+                self.log.append(l);
+            }
+        }
+        terminal.draw(|f| self.render(f, countdown))?;
+        Ok(())
+    }
+
+    fn render(&self, f: &mut Frame, countdown: Option<&str>) {
+        let [main, bottom] = Layout::vertical([Constraint::Min(0), Constraint::Length(7)]).areas(f.area());
+        let [left, right] = Layout::horizontal([Constraint::Length(36), Constraint::Min(0)]).areas(main);
+        let [outputs, saved] = Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(left);
+        let [fields, metrics] = Layout::vertical([Constraint::Length(FIELDS.len() as u16 + 2), Constraint::Min(0)]).areas(right);
+        let block = |title: &str, pane: Option<Pane>| {
+            let b = Block::default().borders(Borders::ALL).title(title.to_string());
+            if pane.is_some() && pane == Some(self.pane) {
+                b.border_style(Style::default().fg(Color::Yellow))
+            } else {
+                b
+            }
+        };
+        let selectable = |text: String, selected: bool| {
+            if selected {
+                Line::from(Span::styled(format!("> {}", text), Style::default().add_modifier(Modifier::BOLD)))
+            } else {
+                Line::from(format!("  {}", text))
+            }
+        };
+
+        let lines: Vec<Line> = self.outputs.iter().enumerate().map(|(i, o)| {
+            let current = o.current.as_ref().map(|m| format!("{}x{} {:.2} Hz", m.width, m.height, m.rate)).unwrap_or_else(|| String::from("off"));
+            selectable(format!("{:<10} {}", o.display, current), i == self.output)
+        }).collect();
+        f.render_widget(Paragraph::new(lines).block(block("Displays", Some(Pane::Outputs))), outputs);
+
+        let lines: Vec<Line> = self.saved.iter().enumerate().map(|(i, m)| selectable(m.get_name().to_string(), i == self.saved_mode)).collect();
+        f.render_widget(Paragraph::new(lines).block(block("Saved modes", Some(Pane::Saved))), saved);
+
+        let title = match &self.editing {
+            Some(name) => format!("Name: {}_", name),
+            None => format!("Candidate {}", self.name),
+        };
+        let lines: Vec<Line> = match &self.candidate {
+            Some(t) => FIELDS.iter().enumerate().map(|(i, (field, label))| selectable(format!("{:<14} {}", label, value(t, *field)), i == self.field)).collect(),
+            None => vec![Line::from("No mode loaded.")],
+        };
+        f.render_widget(Paragraph::new(lines).block(block(&title, Some(Pane::Timings))), fields);
+
+        let mut lines: Vec<Line> = Vec::new();
+        if let Some(t) = &self.candidate {
+            lines.push(Line::from(format!("{:.3} Hz vertical, {:.3} kHz horizontal, {:.3} MHz pixel clock", t.refresh_rate(), t.line_rate(), t.clock)));
+            let display = &self.outputs[self.output].display;
+            match link_limit(display) {
+                Some((link, limit)) => {
+                    let share = t.clock / limit * 100.0;
+                    let style = if share > 100.0 { Style::default().fg(Color::Red) } else { Style::default() };
+                    lines.push(Line::from(Span::styled(format!("{:.0}% of the ~{:.0} MHz {} limit for {}", share, limit, link, display), style)));
+                }
+                None => lines.push(Line::from(format!("No known link limit for {}.", display))),
+            }
+            if let Err(e) = t.check_order() {
+                lines.push(Line::from(Span::styled(format!("Invalid: {}.", e), Style::default().fg(Color::Red))));
+            }
+        }
+        f.render_widget(Paragraph::new(lines).block(block("Metrics", None)), metrics);
+
+        let mut lines = vec![Line::from("Tab pane  \u{2191}\u{2193} select  Enter load  \u{2190}\u{2192} adjust (Shift \u{d7}10)  c/r cvt/cvt-rb  n name  t test  s save  q quit")];
+        lines.push(match (countdown, &self.status) {
+            (Some(c), _) => Line::from(Span::styled(c.to_string(), Style::default().fg(Color::Yellow))),
+            (None, Ok(s)) => Line::from(s.as_str()),
+            (None, Err(e)) => Line::from(Span::styled(format!("Error: {}", e), Style::default().fg(Color::Red))),
+        });
+        lines.extend(self.log.iter().rev().take(3).rev().map(|l| Line::from(Span::styled(l.as_str(), Style::default().fg(Color::DarkGray)))));
+        f.render_widget(Paragraph::new(lines).block(block("cathode tune", None)), bottom);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings() -> Timings {
+        cathode::import::parse_modelines("Modeline \"a\" 148.5 1920 2008 2052 2200 1080 1084 1089 1125 +hsync +vsync")[0].timings().unwrap()
+    }
+
+    #[test]
+    fn refresh_rate_scales_the_clock() {
+        let mut t = timings();
+        adjust(&mut t, Field::Refresh, 150);
+        assert!((t.refresh_rate() - 75.0).abs() < 0.01);
+        assert_eq!((t.h_total, t.v_total), (2200, 1125));
+    }
+
+    #[test]
+    fn blanking_keeps_the_refresh_rate() {
+        let mut t = timings();
+        adjust(&mut t, Field::HBlank, -80);
+        assert_eq!(t.h_total, 2120);
+        assert!((t.refresh_rate() - 60.0).abs() < 0.01);
+        assert!(t.clock < 148.5);
+    }
+
+    #[test]
+    fn fields_stop_at_zero() {
+        let mut t = timings();
+        adjust(&mut t, Field::VSyncStart, -5000);
+        assert_eq!(t.v_sync_start, 0);
+        assert!(t.check_order().is_err());
+    }
+}
@@ -1,87 +1,91 @@
-use std::{env,fs,process,path,str,thread,time};
-use std::io::Error;
+use std::{env,fs,path,thread,time};
 use std::result::Result;
-use regex::Regex;
-use crate::mode::InputMode;
+use crate::command;
+use crate::error::Error;
 
 
 pub fn filename_or_default(f: Option<&str>,verbose: bool) -> Result<path::PathBuf, Error> {
     let buf = match f {
         Some(n) => {
             if verbose {
-                println!("Using provided filename: {}.",n);
+                say!("Using provided filename: {}.",n);
             }
             let tmp = path::PathBuf::from(n);
-            if !tmp.parent().unwrap().is_dir() {
+            // backups, locks and temporary files are named after the file, so it needs a name
+            if tmp.file_name().is_none() {
+                return Err(Error::InvalidInput(format!("{} is not a file name.", n)));
+            }
+            if let Some(dir) = tmp.parent().filter(|d| !d.as_os_str().is_empty() && !d.is_dir() && !command::dry_run()) {
                 if verbose {
-                    println!("Directory {} or one of its parents does not exist. Creating them", &tmp.to_str().unwrap());
+                    say!("Directory {} or one of its parents does not exist. Creating them", dir.display());
                 }
-                fs::create_dir_all(&tmp.parent().unwrap())?;
+                fs::create_dir_all(dir)?;
             }
             tmp
         },
         None => {
-            let mut tmp = match env::var("XDG_CONFIG_HOME") {
-                Ok(dir) => {
-                    if verbose {
-                        println!("$XDG_CONFIG_HOME is defined; using $XDG_CONFIG_HOME/cathode/modes.yml.");
-                    }
-                    path::PathBuf::from(dir)
-                }
-                Err(_) => {
-                    if verbose {
-                        println!("No filename provided and $XDG_CONFIG_HOME is not set; using $HOME/.config/cathode/modes.yml");
-                    }
-                    let mut p = path::PathBuf::from(env::var("HOME").unwrap());
-                    p.push(".config");
-                    p
-                }
-            };
-            tmp.push("cathode");
-            if !tmp.is_dir() {
+            let tmp = default_filename(verbose)?;
+            let dir = tmp.parent().unwrap();
+            if !dir.is_dir() && !command::dry_run() {
                 if verbose {
-                    println!("Directory {} or one of its parents does not exist. Creating them.", &tmp.to_str().unwrap());
+                    say!("Directory {} or one of its parents does not exist. Creating them.", dir.display());
                 }
-                fs::create_dir_all(&tmp)?;
+                fs::create_dir_all(dir)?;
             }
-            tmp.push("modes.yml");
             tmp
         }
     };
     Ok(buf)
 }
 
-
-
-pub fn get_modes_helper(re: &Regex, verbose: bool) -> Result<Vec<InputMode>, Error> {
-
-    let mut modes: Vec<InputMode> = Vec::new();
-     if cfg!(target_os = "linux") {
-        let mut cmd = process::Command::new("xrandr");
-        cmd.arg("--current");
-        let dispoutput = cmd.output()?;
-        let dispout = str::from_utf8(&dispoutput.stdout).unwrap();
-        // capture groups: display name, default width, height, refresh rate for active displays
-        for cap in re.captures_iter(dispout) {
-            let width = cap[2].to_string();
-            let height = cap[3].to_string();
-            let rate = cap[4].to_string();
-            let display = cap[1].to_string();
-            let name = format!("{}x{}",width,height);
+/// Where the modes file lives when no filename is given; nothing is created
+pub fn default_filename(verbose: bool) -> Result<path::PathBuf, Error> {
+    let mut tmp = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) => {
             if verbose {
-                println!("Found mode {} for display {}: width: {}, height: {}, refresh rate: {}", name, display, width, height, rate);
+                say!("$XDG_CONFIG_HOME is defined; using $XDG_CONFIG_HOME/cathode/modes.yml.");
             }
-            let mode = InputMode::new(&width, &height, &rate, &display, &name);
-            modes.push(mode);
+            path::PathBuf::from(dir)
         }
-    }
-    Ok(modes)
+        Err(_) => {
+            if verbose {
+                say!("No filename provided and $XDG_CONFIG_HOME is not set; using $HOME/.config/cathode/modes.yml");
+            }
+            let home = env::var("HOME").map_err(|_| Error::InvalidInput("Neither $XDG_CONFIG_HOME nor $HOME is set; pass the modes file with --filename.".to_string()))?;
+            let mut p = path::PathBuf::from(home);
+            p.push(".config");
+            p
+        }
+    };
+    tmp.push("cathode");
+    tmp.push("modes.yml");
+    Ok(tmp)
 }
 
 
+
+// Leaves names like 1920x1080_75.00 alone so scripts and printed commands stay readable
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "_.:+-/".contains(c)) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
 pub fn print_countdown(timeout: u64) {
     for i in 0..timeout {
-        println!("Reverting in {} secs",timeout-i);
+        say!("Reverting in {} secs",timeout-i);
         thread::sleep(time::Duration::from_secs(1));
     }
 }
+
+
+// 64-bit FNV-1a; unlike DefaultHasher its output is stable across builds, so it can be written to disk
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
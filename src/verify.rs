@@ -0,0 +1,148 @@
+use std::result::Result;
+use serde::Serialize;
+use crate::backend::{self,DisplayBackend,Vblank};
+use crate::error::Error;
+use crate::output;
+
+
+/// How the vblanks of a display lined up with the refresh rate of its mode
+#[derive(Clone,Debug,Serialize)]
+pub struct Measurement {
+    pub display: String,
+    /// the refresh rate the mode's timings work out to, in Hz
+    pub expected: f64,
+    /// the rate the vblanks actually came at, in Hz
+    pub measured: f64,
+    /// how far the measured rate is from the expected one, in percent
+    pub deviation: f64,
+    /// how many frames the measurement spanned
+    pub frames: u64,
+    /// the standard deviation of the frame time, in microseconds
+    pub jitter: f64,
+    /// the frame time furthest from the mean, as a difference in microseconds
+    pub worst: f64,
+    /// frames whose vblank never came: the gap between two vblanks was long enough for more
+    /// frames than the frame counter advanced by
+    pub skipped: u64,
+}
+
+// Frame rates further off than this are reported as not matching the mode
+const TOLERANCE: f64 = 0.5;
+
+/// Works out the real refresh rate, jitter and skipped frames from a run of vblanks.
+/// Returns None if the vblanks span no time at all.
+pub fn analyze(display: &str, vblanks: &[Vblank], expected: f64) -> Option<Measurement> {
+    let first = vblanks.first()?;
+    let last = vblanks.last()?;
+    let frames = last.sequence.checked_sub(first.sequence)?;
+    let elapsed = last.usec.checked_sub(first.usec)? as f64;
+    if frames == 0 || elapsed == 0.0 {
+        return None;
+    }
+    let period = 1_000_000.0 / expected;
+    let mut times: Vec<f64> = Vec::new();
+    let mut skipped = 0;
+    for pair in vblanks.windows(2) {
+        let n = pair[1].sequence.saturating_sub(pair[0].sequence);
+        let dt = pair[1].usec.saturating_sub(pair[0].usec) as f64;
+        if n == 0 {
+            continue;
+        }
+        times.push(dt / n as f64);
+        let missing = (dt / period).round() as u64;
+        skipped += missing.saturating_sub(n);
+    }
+    let mean = elapsed / frames as f64;
+    let jitter = (times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / times.len() as f64).sqrt();
+    let worst = times.iter().map(|t| (t - mean).abs()).fold(0.0, f64::max);
+    let measured = 1_000_000.0 / mean;
+    Some(Measurement {
+        display: display.to_string(),
+        expected,
+        measured,
+        deviation: (measured - expected) / expected * 100.0,
+        frames,
+        jitter,
+        worst,
+        skipped,
+    })
+}
+
+/// Measures the vblanks of `display` (or the first display running a mode) for `seconds`
+/// and reports how they compare to the refresh rate of the mode it is running.
+pub fn verify(backend: &mut dyn DisplayBackend, display: Option<&str>, seconds: u64, verbose: bool) -> Result<(), Error> {
+    let statuses = backend.status(verbose)?;
+    let outputs: Vec<backend::Output> = statuses.iter().filter(|s| s.connected).map(|s| s.output.clone()).collect();
+    let output = match display {
+        Some(d) => backend::find_output(d, &outputs).ok_or_else(|| Error::DisplayNotFound(format!("Display {} is not connected.", d)))?,
+        None => outputs.iter().find(|o| o.current.is_some()).ok_or_else(|| Error::DisplayNotFound(String::from("No displays are running a mode.")))?,
+    };
+    let current = output.current.as_ref().ok_or_else(|| Error::Unsupported(format!("Display {} is switched off.", output.display)))?;
+    // the rate from the exact timings, where the backend has them, rather than the rounded one
+    let expected = statuses.iter()
+        .find(|s| s.output.display == output.display)
+        .and_then(|s| s.timings.as_ref())
+        .map(|t| t.refresh_rate())
+        .unwrap_or(current.rate);
+    let count = (expected * seconds as f64).ceil() as u32 + 1;
+    if !output::json() {
+        say!("Measuring display {} running {} for {} secs.", output.display, current.name, seconds);
+    }
+    let vblanks = backend.vblanks(&output.display, count, verbose)?;
+    let m = analyze(&output.display, &vblanks, expected)
+        .ok_or_else(|| Error::backend(format!("Display {} reported no usable vblank timestamps.", output.display)))?;
+    if output::json() {
+        return output::emit(&m);
+    }
+    out!("Display {}: expected {:.3} Hz, measured {:.3} Hz ({:+.3}%) over {} frames.", m.display, m.expected, m.measured, m.deviation, m.frames);
+    out!("Frame time jitter {:.1} µs, worst {:.1} µs; {} skipped frames.", m.jitter, m.worst, m.skipped);
+    if m.deviation.abs() > TOLERANCE {
+        warn!("Display {} is not running at the refresh rate of its mode; the driver may have adjusted the pixel clock.", m.display);
+    }
+    if m.skipped > 0 {
+        warn!("Display {} skipped {} frames.", m.display, m.skipped);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vblanks(times: &[(u64, u64)]) -> Vec<Vblank> {
+        times.iter().map(|(sequence, usec)| Vblank { sequence: *sequence, usec: *usec }).collect()
+    }
+
+    #[test]
+    fn steady_vblanks_match_the_mode() {
+        let v: Vec<Vblank> = (0..61).map(|i| Vblank { sequence: 100 + i, usec: 5_000 + i * 16_667 }).collect();
+        let m = analyze("DP-1", &v, 60.0).unwrap();
+        assert_eq!(m.frames, 60);
+        assert_eq!(m.skipped, 0);
+        assert!((m.measured - 60.0).abs() < 0.01);
+        assert!(m.jitter < 1.0);
+    }
+
+    #[test]
+    fn vblanks_missed_by_the_caller_are_not_skipped_frames() {
+        // the counter moved on by two, and so did the clock
+        let m = analyze("DP-1", &vblanks(&[(0, 0), (1, 10_000), (3, 30_000), (4, 40_000)]), 100.0).unwrap();
+        assert_eq!(m.skipped, 0);
+        assert!(m.jitter < 1.0);
+    }
+
+    #[test]
+    fn long_gaps_are_skipped_frames() {
+        let m = analyze("DP-1", &vblanks(&[(0, 0), (1, 10_000), (2, 30_000), (3, 40_000)]), 100.0).unwrap();
+        assert_eq!(m.skipped, 1);
+        assert!(m.measured < 100.0);
+        assert!(m.worst > 6_000.0);
+    }
+
+    #[test]
+    fn no_time_is_no_measurement() {
+        assert!(analyze("DP-1", &vblanks(&[(5, 1_000)]), 60.0).is_none());
+        assert!(analyze("DP-1", &[], 60.0).is_none());
+    }
+}
@@ -0,0 +1,418 @@
+// Runs the cathode binary against fake xrandr and cvt scripts, with its config directory in a
+// temporary directory, to check that command line flags and settings actually take effect.
+
+use std::{env,fs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command,Output,Stdio};
+use tempfile::TempDir;
+
+
+const XRANDR: &str = r##"#!/bin/sh
+echo "xrandr $*" >> "$CATHODE_TEST_LOG"
+case "$*" in
+  *--verbose*) cat <<'OUT'
+Screen 0: minimum 320 x 200, current 1920 x 1080, maximum 16384 x 16384
+DP-1 connected primary 1920x1080+0+0 (0x4a) normal (normal left inverted right x axis y axis) 597mm x 336mm
+	Identifier: 0x42
+  1920x1080 (0x4a) 148.500MHz +HSync +VSync *current +preferred
+        h: width  1920 start 2008 end 2052 total 2200 skew    0 clock  67.50KHz
+        v: height 1080 start 1084 end 1089 total 1125           clock  60.00Hz
+HDMI-1 disconnected (normal left inverted right x axis y axis)
+HDMI-2 connected 1280x1024+1920+0 (0x50) normal (normal left inverted right x axis y axis) 376mm x 301mm
+	Identifier: 0x43
+  1280x1024 (0x50) 135.000MHz +HSync +VSync *current +preferred
+        h: width  1280 start 1296 end 1440 total 1688 skew    0 clock  79.98KHz
+        v: height 1024 start 1025 end 1028 total 1066           clock  75.02Hz
+OUT
+  ;;
+esac
+"##;
+
+const CVT: &str = r##"#!/bin/sh
+echo "cvt $*" >> "$CATHODE_TEST_LOG"
+echo "# 800x600 59.86 Hz (CVT 0.48M3) hsync: 37.35 kHz; pclk: 38.25 MHz"
+echo 'Modeline "800x600_60.00"   38.25  800 832 912 1024  600 603 607 624 -hsync +vsync'
+"##;
+
+struct Sandbox {
+    dir: TempDir,
+}
+
+impl Sandbox {
+    fn new() -> Sandbox {
+        let dir = TempDir::new().unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        for (name, script) in [("xrandr", XRANDR), ("cvt", CVT)].iter() {
+            let p = bin.join(name);
+            fs::write(&p, script).unwrap();
+            fs::set_permissions(&p, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        Sandbox { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn default_file(&self) -> PathBuf {
+        self.path("config/cathode/modes.yml")
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let path = format!("{}:{}", self.path("bin").display(), env::var("PATH").unwrap_or_default());
+        Command::new(env!("CARGO_BIN_EXE_cathode"))
+            .arg("--backend").arg("xrandr")
+            .args(args)
+            .env("PATH", path)
+            .env("HOME", self.dir.path())
+            .env("XDG_CONFIG_HOME", self.path("config"))
+            .env("CATHODE_TEST_LOG", self.path("log"))
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    }
+
+    // Runs cathode and fails the test unless it succeeds
+    fn ok(&self, args: &[&str]) -> String {
+        let out = self.run(args);
+        assert!(out.status.success(), "cathode {:?} failed: {}", args, String::from_utf8_lossy(&out.stderr));
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    fn log(&self) -> String {
+        fs::read_to_string(self.path("log")).unwrap_or_default()
+    }
+
+    fn read(&self, p: &PathBuf) -> String {
+        fs::read_to_string(p).unwrap_or_default()
+    }
+}
+
+fn add_to(s: &Sandbox, file: &str) {
+    s.ok(&["-f", file, "add", "-w", "800", "-h", "600", "-r", "60", "-n", "test", "-d", "DP-1"]);
+}
+
+
+#[test]
+fn add_saves_to_the_given_file() {
+    let s = Sandbox::new();
+    let custom = s.path("custom.yml");
+    add_to(&s, custom.to_str().unwrap());
+    assert!(s.read(&custom).contains("name: test"));
+    assert!(!s.read(&s.default_file()).contains("name: test"));
+    assert!(s.log().contains("cvt 800 600 60"));
+    assert!(s.log().contains("xrandr --newmode test 38.25"));
+}
+
+#[test]
+fn apply_looks_up_and_persists_in_the_given_file() {
+    let s = Sandbox::new();
+    let custom = s.path("custom.yml");
+    let f = custom.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--output", "json", "apply", "-n", "test", "-d", "DP-1", "--persist"]);
+    assert!(out.contains(r#""applied":true"#));
+    assert!(out.contains(r#""persisted":true"#));
+    assert!(s.log().contains("xrandr --output DP-1 --mode test"));
+    assert!(s.read(&custom).contains("persisted:\n  - display: DP-1\n    mode: test"));
+    assert!(!s.read(&s.default_file()).contains("test"));
+}
+
+#[test]
+fn apply_without_the_file_does_not_find_the_mode() {
+    let s = Sandbox::new();
+    add_to(&s, s.path("custom.yml").to_str().unwrap());
+    let out = s.run(&["apply", "-n", "test", "-d", "DP-1"]);
+    assert_eq!(out.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("No mode named test is saved."));
+}
+
+#[test]
+fn file_setting_is_used_when_no_file_is_given() {
+    let s = Sandbox::new();
+    let custom = s.path("custom.yml");
+    s.ok(&["config", "set", "file", custom.to_str().unwrap()]);
+    s.ok(&["add", "-w", "800", "-h", "600", "-r", "60", "-n", "test", "-d", "DP-1"]);
+    assert!(s.read(&custom).contains("name: test"));
+    s.ok(&["apply", "-n", "test", "-d", "DP-1"]);
+}
+
+#[test]
+fn apply_honors_timeout() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--dry-run", "apply", "-n", "test", "-d", "DP-1", "--timeout", "3"]);
+    assert!(out.contains("Would test mode test on display DP-1 for 3 secs."), "{}", out);
+}
+
+#[test]
+fn timeout_setting_is_used_when_no_timeout_is_given() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    s.ok(&["config", "set", "timeout", "7"]);
+    let out = s.ok(&["-f", f, "--dry-run", "apply", "-n", "test", "-d", "DP-1", "--test"]);
+    assert!(out.contains("for 7 secs."), "{}", out);
+}
+
+#[test]
+fn add_honors_timeout_and_timing() {
+    let s = Sandbox::new();
+    let out = s.ok(&["--dry-run", "add", "-w", "800", "-h", "600", "-r", "60", "-d", "DP-1", "-t", "4", "--timing", "cvt-rb"]);
+    assert!(out.contains("for 4 secs."), "{}", out);
+    assert!(s.log().contains("cvt -r 800 600 60"));
+    // a dry run only queries xrandr
+    assert!(!s.log().contains("--newmode"));
+}
+
+#[test]
+fn a_symlinked_modes_file_stays_a_link() {
+    let s = Sandbox::new();
+    let real = s.path("dotfiles/modes.yml");
+    fs::create_dir(s.path("dotfiles")).unwrap();
+    fs::write(&real, "").unwrap();
+    fs::set_permissions(&real, fs::Permissions::from_mode(0o600)).unwrap();
+    let link = s.path("modes.yml");
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+    add_to(&s, link.to_str().unwrap());
+    add_to(&s, link.to_str().unwrap());
+    assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    assert!(s.read(&real).contains("name: test"));
+    assert_eq!(fs::metadata(&real).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn only_the_latest_backups_are_kept() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    add_to(&s, f.to_str().unwrap());
+    for secs in 1..=6 {
+        fs::write(s.path(&format!("custom.yml.{}.bak", secs)), "").unwrap();
+    }
+    s.ok(&["-f", f.to_str().unwrap(), "add", "-w", "800", "-h", "600", "-r", "60", "-n", "again", "-d", "DP-1"]);
+    let mut backups: Vec<String> = fs::read_dir(s.dir.path()).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|n| n.ends_with(".bak"))
+        .collect();
+    backups.sort();
+    assert_eq!(backups.len(), 5, "{:?}", backups);
+    assert!(!backups.contains(&String::from("custom.yml.1.bak")));
+    assert!(!backups.contains(&String::from("custom.yml.2.bak")));
+}
+
+#[test]
+fn dry_run_creates_no_files() {
+    let s = Sandbox::new();
+    let f = s.path("new/custom.yml");
+    let out = s.ok(&["-f", f.to_str().unwrap(), "--dry-run", "add", "-w", "800", "-h", "600", "-r", "60", "-n", "test", "-d", "DP-1"]);
+    assert!(out.contains("+  - name: test"), "{}", out);
+    assert!(!s.path("new").exists());
+    assert!(!s.path("config").exists());
+
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    add_to(&s, f.to_str().unwrap());
+    let mut edid = vec![0u8; 128];
+    edid[0..8].copy_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+    let input = s.path("edid.bin");
+    fs::write(&input, edid).unwrap();
+    let patched = s.path("patched.bin");
+    let out = s.ok(&["-f", f.to_str().unwrap(), "--dry-run", "export", "edid", "-n", "test", "-d", "DP-1", "-i", input.to_str().unwrap(), "-o", patched.to_str().unwrap()]);
+    assert!(out.contains("Would write"), "{}", out);
+    assert!(!patched.exists());
+}
+
+#[test]
+fn untested_mode_is_reverted_without_an_answer() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--output", "json", "apply", "-n", "test", "-d", "DP-1", "-t", "1"]);
+    assert!(out.contains(r#""applied":false"#), "{}", out);
+    assert!(s.log().contains("xrandr --output DP-1 --mode 1920x1080 --rate 60.00"));
+}
+
+#[test]
+fn export_uses_the_given_file() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let script = s.ok(&["-f", f, "export", "xrandr", "-n", "test", "-d", "DP-1"]);
+    assert!(script.contains("xrandr --newmode test"));
+    assert_eq!(s.run(&["export", "xrandr", "-n", "test"]).status.code(), Some(3));
+}
+
+#[test]
+fn kernel_parameter_warns_about_timings_the_kernel_would_change() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.run(&["-f", f, "export", "kernel", "-n", "test", "-d", "DP-1"]);
+    assert!(String::from_utf8_lossy(&out.stdout).contains("video=DP-1:800x600M@60"));
+    assert!(!String::from_utf8_lossy(&out.stderr).contains("CVT timings"));
+    let modelines = s.path("xorg.conf");
+    fs::write(&modelines, "Modeline \"tight\" 36.00 800 824 896 1000 600 601 604 620 -hsync +vsync\n").unwrap();
+    s.ok(&["-f", f, "import", modelines.to_str().unwrap()]);
+    let out = s.run(&["-f", f, "export", "kernel", "-n", "tight", "-d", "DP-1"]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("does not have the CVT timings"));
+}
+
+#[test]
+fn exported_yaml_is_a_modes_file() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    add_to(&s, f.to_str().unwrap());
+    let yaml = s.ok(&["-f", f.to_str().unwrap(), "export", "yaml"]);
+    assert!(yaml.contains("version: 2"), "{}", yaml);
+    let exported = s.path("exported.yml");
+    fs::write(&exported, yaml).unwrap();
+    let script = s.ok(&["-f", exported.to_str().unwrap(), "export", "xrandr", "-n", "test"]);
+    assert!(script.contains("xrandr --newmode test"));
+}
+
+#[test]
+fn export_prints_one_json_line() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let modes = s.ok(&["-f", f, "--output", "json", "export", "yaml"]);
+    assert_eq!(modes.lines().count(), 1);
+    assert!(modes.starts_with(r#"{"modes":[{"#));
+    let script = s.ok(&["-f", f, "--output", "json", "export", "xrandr", "-n", "test"]);
+    assert_eq!(script.lines().count(), 1);
+    assert!(script.contains(r##""script":"#!/bin/sh\n"##));
+}
+
+#[test]
+fn invalid_numbers_are_rejected() {
+    let s = Sandbox::new();
+    for args in [["add", "-w", "0"], ["add", "-r", "fast"], ["add", "-t", "0"], ["daemon", "-i", "0"], ["daemon", "-i", "soon"]].iter() {
+        let out = s.run(args);
+        assert_eq!(out.status.code(), Some(2), "{:?}", args);
+    }
+    assert_eq!(s.log(), "");
+}
+
+#[test]
+fn settings_lookup_creates_no_default_file() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    s.ok(&["-f", f.to_str().unwrap(), "add", "-w", "800", "-h", "600", "-r", "60", "-n", "test", "-d", "DP-1"]);
+    assert!(s.read(&f).contains("name: test"));
+    assert!(!s.path("config").exists());
+}
+
+#[test]
+fn files_without_a_name_are_rejected() {
+    let s = Sandbox::new();
+    for f in ["/", ".."].iter() {
+        let out = s.run(&["-f", f, "profile", "-n", "home"]);
+        assert_eq!(out.status.code(), Some(2), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+}
+
+#[test]
+fn add_takes_defaults_from_the_chosen_display() {
+    let s = Sandbox::new();
+    s.ok(&["--dry-run", "add", "-d", "HDMI-2", "-r", "60"]);
+    assert!(s.log().contains("cvt 1280 1024 60"), "{}", s.log());
+    s.ok(&["--dry-run", "add", "-h", "900"]);
+    assert!(s.log().contains("cvt 1920 900 60"), "{}", s.log());
+}
+
+#[test]
+fn add_for_a_disconnected_display_fails() {
+    let s = Sandbox::new();
+    let out = s.run(&["add", "-d", "HDMI-1", "-r", "75"]);
+    assert_eq!(out.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Display HDMI-1 is not connected."));
+    assert!(!s.log().contains("cvt"));
+}
+
+#[test]
+fn add_with_no_connected_displays_fails() {
+    let s = Sandbox::new();
+    fs::write(s.path("bin/xrandr"), "#!/bin/sh\necho 'Screen 0: minimum 320 x 200, current 0 x 0, maximum 16384 x 16384'\necho 'DP-1 disconnected (normal left inverted right x axis y axis)'\n").unwrap();
+    let out = s.run(&["add", "-w", "800", "-h", "600", "-r", "60"]);
+    assert_eq!(out.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("No displays are connected."));
+}
+
+#[test]
+fn apply_to_several_displays_uses_one_xrandr_call() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--output", "json", "apply", "-n", "test", "-d", "DP-1", "-d", "HDMI-2", "--persist"]);
+    assert!(out.contains(r#""displays":["DP-1","HDMI-2"]"#), "{}", out);
+    assert!(s.log().contains("xrandr --addmode HDMI-2 test"));
+    assert!(s.log().contains("xrandr --output DP-1 --mode test --output HDMI-2 --mode test\n"), "{}", s.log());
+    let saved = s.read(&s.path("custom.yml"));
+    assert!(saved.contains("display: DP-1") && saved.contains("display: HDMI-2"));
+}
+
+#[test]
+fn apply_to_all_tests_and_reverts_every_display() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--dry-run", "apply", "-n", "test", "-d", "all", "--test"]);
+    assert!(out.contains("Would test mode test on display DP-1, HDMI-2"), "{}", out);
+    assert!(out.contains("Would run: xrandr --output DP-1 --mode 1920x1080 --rate 60.00 --output HDMI-2 --mode 1280x1024 --rate 75.02"), "{}", out);
+}
+
+#[test]
+fn pattern_is_shown_while_a_mode_is_tested() {
+    let s = Sandbox::new();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    add_to(&s, f);
+    let out = s.ok(&["-f", f, "--dry-run", "pattern", "-n", "test", "-d", "HDMI-2", "-t", "2"]);
+    assert!(out.contains("Would show a test pattern on display HDMI-2."), "{}", out);
+    assert!(out.contains("Would test mode test on display HDMI-2 for 2 secs."), "{}", out);
+    assert!(out.contains("Would run: xrandr --output HDMI-2 --mode 1280x1024 --rate 75.02"), "{}", out);
+}
+
+#[test]
+fn interlaced_mode_flags_are_passed_separately() {
+    let s = Sandbox::new();
+    fs::write(s.path("bin/cvt"), "#!/bin/sh\necho \"cvt $*\" >> \"$CATHODE_TEST_LOG\"\necho 'Modeline \"1920x1080i_60.00\"   85.25  1920 2040 2240 2560  1080 1085 1095 1112 interlace -hsync +vsync'\n").unwrap();
+    // log every argument in brackets, to tell apart flags passed together from ones passed separately
+    let xrandr = XRANDR.replace(r#"echo "xrandr $*""#, r#"{ printf 'xrandr'; printf ' [%s]' "$@"; echo; }"#);
+    fs::write(s.path("bin/xrandr"), xrandr).unwrap();
+    let f = s.path("custom.yml");
+    let f = f.to_str().unwrap();
+    s.ok(&["-f", f, "add", "-w", "1920", "-h", "1080", "-r", "60", "-d", "DP-1", "--interlaced"]);
+    assert!(s.log().contains("cvt -i 1920 1080 60"), "{}", s.log());
+    assert!(s.log().contains("[1112] [interlace] [-hsync] [+vsync]"), "{}", s.log());
+    assert!(s.read(&s.path("custom.yml")).contains("name: 1920x1080i_60"));
+    let out = s.ok(&["-f", f, "export", "modeline", "-n", "1920x1080i_60"]);
+    assert!(out.contains("1080 1085 1095 1112 interlace -hsync +vsync"), "{}", out);
+}
+
+#[test]
+fn profile_restores_the_refresh_rate_it_saved() {
+    let s = Sandbox::new();
+    // 1920x1080 at 60 Hz is listed first, but the display is running 1920x1080 at 144 Hz
+    let xrandr = XRANDR.replace("  1920x1080 (0x4a) 148.500MHz +HSync +VSync *current +preferred\n        h: width  1920 start 2008 end 2052 total 2200 skew    0 clock  67.50KHz\n        v: height 1080 start 1084 end 1089 total 1125           clock  60.00Hz\n",
+        "  1920x1080 (0x4a) 148.500MHz +HSync +VSync +preferred\n        h: width  1920 start 2008 end 2052 total 2200 skew    0 clock  67.50KHz\n        v: height 1080 start 1084 end 1089 total 1125           clock  60.00Hz\n  1920x1080 (0x4b) 325.080MHz +HSync -VSync *current\n        h: width  1920 start 1968 end 2000 total 2080 skew    0 clock 156.29KHz\n        v: height 1080 start 1083 end 1088 total 1085           clock 144.00Hz\n");
+    assert_ne!(xrandr, XRANDR);
+    fs::write(s.path("bin/xrandr"), xrandr).unwrap();
+    s.ok(&["profile", "-n", "gaming"]);
+    assert!(s.read(&s.default_file()).contains("rate: 144"), "{}", s.read(&s.default_file()));
+    s.ok(&["restore"]);
+    assert!(s.log().contains("xrandr --output DP-1 --mode 1920x1080 --rate 144.00"), "{}", s.log());
+    assert!(!s.log().contains("--rate 60.00"), "{}", s.log());
+}